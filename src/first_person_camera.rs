@@ -1,8 +1,28 @@
+extern crate bincode;
+extern crate image;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
 extern crate nalgebra;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+
+/// The drafts this scene shares with the library, built from the same files.
+#[path = "."]
+#[allow(dead_code)]
+mod drafts {
+    #[cfg(test)]
+    pub mod golden;
+    pub mod layout;
+    pub mod markov;
+    pub mod markov_analysis;
+    #[cfg(test)]
+    pub mod recursive_template;
+    pub mod software_backend;
+}
 
 use mursten::{Application, Backend, Data};
 use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
@@ -11,23 +31,58 @@ use mursten_blocks::time::{Clock, ClockUpdater, OnTick, Tick};
 use mursten_blocks::input::{Key, KeyboardEvent, OnKeyboard, KeyboardUpdater};
 use mursten_blocks::mesh_renderer::{GetMeshes, IntoMesh, MeshRenderer};
 use mursten_vulkan_backend::VulkanBackend;
+use std::env;
+use std::process;
 use std::time::Duration;
 
-use rand::distributions::normal::Normal;
-use rand::distributions::IndependentSample;
+use drafts::layout::{LayoutGenerator, Terrain, Tile};
+use drafts::software_backend::{software_backend_from_args, SoftwareCameraUpdater,
+                               SoftwareMeshRenderer};
 
 use nalgebra::*;
 
 
 pub fn main() {
-    let backend = VulkanBackend::new();
-    let scene = Scene::new();
-    Application::new(backend)
-        .add_updater(ClockUpdater::new())
-        .add_updater(CameraUpdater::new())
-        .add_updater(KeyboardUpdater::new())
-        .add_renderer(MeshRenderer::new())
-        .run(scene);
+    let seed = match seed_from_args() {
+        Ok(seed) => seed,
+        Err(e) => {
+            eprintln!("first_person_camera: {}", e);
+            process::exit(2);
+        }
+    };
+    let scene = Scene::new(seed);
+    match software_backend_from_args() {
+        Some(backend) => {
+            Application::new(backend)
+                .add_updater(ClockUpdater::new())
+                .add_updater(SoftwareCameraUpdater)
+                .add_renderer(SoftwareMeshRenderer)
+                .run(scene);
+        }
+        None => {
+            Application::new(VulkanBackend::new())
+                .add_updater(ClockUpdater::new())
+                .add_updater(CameraUpdater::new())
+                .add_updater(KeyboardUpdater::new())
+                .add_renderer(MeshRenderer::new())
+                .run(scene);
+        }
+    }
+}
+
+/// `--seed N` picks the first of the layouts the floor goes through, 0 by
+/// default.
+fn seed_from_args() -> Result<u64, String> {
+    let args: Vec<String> = env::args().collect();
+    match args.iter().position(|a| a == "--seed") {
+        None => Ok(0),
+        Some(i) => match args.get(i + 1) {
+            None => Err("--seed needs a number".to_string()),
+            Some(seed) => seed
+                .parse()
+                .map_err(|_| format!("{:?} is not a seed, it should be a number", seed)),
+        },
+    }
 }
 
 struct Scene {
@@ -36,12 +91,14 @@ struct Scene {
     next_change: Duration,
     player: Player,
     floor: Vec<Platform>,
+    layouts: LayoutGenerator,
+    seed: u64,
     skybox: Skybox,
     cube: Cube,
 }
 
 impl Scene {
-    pub fn new() -> Self {
+    pub fn new(seed: u64) -> Self {
         Scene {
             clock: Clock::new(),
             paused: false,
@@ -56,6 +113,8 @@ impl Scene {
                 }
                 v
             },
+            layouts: LayoutGenerator::new(20, 20),
+            seed,
             skybox: Skybox::new(),
             cube: Cube::new(),
         }
@@ -96,6 +155,18 @@ enum PlatformColor {
     Blue(bool),
 }
 
+impl<'a> From<&'a Tile> for PlatformColor {
+    fn from(tile: &Tile) -> Self {
+        use PlatformColor::*;
+        match tile.terrain {
+            Terrain::Ridge | Terrain::Hill => Red(tile.terrain == Terrain::Ridge),
+            Terrain::Ground => Green(tile.bright),
+            Terrain::Path => Green(true),
+            Terrain::Water => Blue(tile.bright),
+        }
+    }
+}
+
 impl Into<Vector4<f32>> for PlatformColor {
     fn into(self) -> Vector4<f32> {
         use PlatformColor::*;
@@ -199,7 +270,6 @@ impl Data for Scene {}
 
 impl OnTick for Scene {
     fn on_tick(&mut self, tick: Tick) {
-        use PlatformColor::*;
         if !self.paused {
             self.clock += tick;
         }
@@ -207,20 +277,11 @@ impl OnTick for Scene {
 
         if self.clock.delta() >= self.next_change {
             self.next_change = Duration::from_secs(1);
-            {
-                let normal = Normal::new(0.2, 0.2);
-                let mut rng = rand::thread_rng();
-                for platform in self.floor.iter_mut() {
-                    let ph = normal.ind_sample(&mut rng) as f32;
-                    platform.target_height = ph;
-                    if ph >= 0.3 {
-                        platform.color = Red(ph > 0.36);
-                    } else if ph >= 0.15 {
-                        platform.color = Green(ph > 0.2);
-                    } else {
-                        platform.color = Blue(ph > 0.05);
-                    }
-                }
+            let layout = self.layouts.generate(self.seed);
+            self.seed += 1;
+            for (platform, tile) in self.floor.iter_mut().zip(layout.tiles()) {
+                platform.target_height = tile.height;
+                platform.color = tile.into();
             }
         } else {
             self.next_change -= self.clock.delta();
@@ -286,3 +347,65 @@ impl OnKeyboard for Scene {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::Scene;
+    use drafts::golden::FixedClockUpdater;
+    use drafts::layout::LayoutGenerator;
+    use drafts::recursive_template::{ChainMut, ProcessorMut, RendererProcessor, System,
+                                     UpdaterProcessor};
+    use mursten::dummy::DummyBackend;
+    use mursten::{Renderer, Updater};
+    use mursten_blocks::mesh_renderer::GetMeshes;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    /// Stands in for the AI, keeping the player walking forward.
+    struct Walk;
+
+    impl<B> ProcessorMut<B, Scene> for Walk {
+        fn process_mut(&mut self, _: &mut B, scene: &mut Scene) {
+            scene.player.moving_towards.z = 1.0;
+        }
+    }
+
+    struct CountMeshes {
+        counts: Rc<RefCell<Vec<usize>>>,
+    }
+
+    impl<B> Renderer<B, Scene> for CountMeshes {
+        fn render(&mut self, _: &mut B, scene: &Scene) {
+            self.counts.borrow_mut().push(scene.mesh_iter().count());
+        }
+    }
+
+    #[test]
+    fn chain_drives_the_scene() {
+        let counts = Rc::new(RefCell::new(Vec::new()));
+        let mut updater = System
+            .add_mut(Walk)
+            .add_mut(UpdaterProcessor::new(FixedClockUpdater::new(Duration::from_millis(250))))
+            .add_mut(RendererProcessor::new(CountMeshes {
+                counts: counts.clone(),
+            }))
+            .into_updater();
+        let mut backend = DummyBackend::<Scene>::new();
+        let mut scene = Scene::new(7);
+
+        for _ in 0..4 {
+            updater.update(&mut backend, &mut scene);
+        }
+
+        // A second walking forward at two units a second
+        assert!((scene.player.transform[(2, 3)] - 2.0).abs() < 1e-4);
+        // After a second the floor heads for the first layout
+        let layout = LayoutGenerator::new(20, 20).generate(7);
+        assert_eq!(scene.seed, 8);
+        for (platform, tile) in scene.floor.iter().zip(layout.tiles()) {
+            assert_eq!(platform.target_height, tile.height);
+        }
+        // 400 platforms and the cube, every frame
+        assert_eq!(*counts.borrow(), vec![401; 4]);
+    }
+}
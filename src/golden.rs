@@ -0,0 +1,530 @@
+use drafts::software_backend::SoftwareBackend;
+use image::{self, ImageError, RgbaImage};
+use mursten::{Data, Renderer, Updater};
+use mursten_blocks::time::{OnTick, Tick};
+use nalgebra::Vector4;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Writes every reference instead of comparing against it when set.
+pub const UPDATE_VAR: &str = "UPDATE_GOLDEN";
+
+/// Ticks of a fixed length, so a scene steps the same way on every run.
+pub struct FixedClockUpdater {
+    delta: Duration,
+}
+
+impl FixedClockUpdater {
+    pub fn new(delta: Duration) -> Self {
+        FixedClockUpdater { delta }
+    }
+}
+
+impl Default for FixedClockUpdater {
+    /// A 60 fps tick.
+    fn default() -> Self {
+        FixedClockUpdater::new(Duration::new(0, 16_666_667))
+    }
+}
+
+impl<B, D> Updater<B, D> for FixedClockUpdater
+where
+    D: Data + OnTick,
+{
+    fn update(&mut self, _: &mut B, data: &mut D) {
+        data.on_tick(Tick::new(self.delta));
+    }
+}
+
+/// Changes the data at given ticks, to stand in for input devices.
+pub struct Script<D> {
+    steps: Vec<(usize, Box<FnMut(&mut D)>)>,
+    tick: usize,
+}
+
+impl<D> Script<D> {
+    pub fn new() -> Self {
+        Script {
+            steps: Vec::new(),
+            tick: 0,
+        }
+    }
+    /// Runs `step` on the `tick`-th update, counting from 0.
+    pub fn at<F>(mut self, tick: usize, step: F) -> Self
+    where
+        F: FnMut(&mut D) + 'static,
+    {
+        self.steps.push((tick, Box::new(step)));
+        self
+    }
+}
+
+impl<B, D> Updater<B, D> for Script<D>
+where
+    D: Data,
+{
+    fn update(&mut self, _: &mut B, data: &mut D) {
+        for &mut (tick, ref mut step) in self.steps.iter_mut() {
+            if tick == self.tick {
+                step(data);
+            }
+        }
+        self.tick += 1;
+    }
+}
+
+/// Runs updaters and renderers on a `SoftwareBackend` the way the backend's
+/// own loop does, but for a given number of ticks, keeping the last frame.
+pub struct Harness<D>
+where
+    D: Data,
+{
+    backend: SoftwareBackend,
+    updaters: Vec<Box<Updater<SoftwareBackend, D>>>,
+    renderers: Vec<Box<Renderer<SoftwareBackend, D>>>,
+}
+
+impl<D> Harness<D>
+where
+    D: Data,
+{
+    pub fn new(width: u32, height: u32) -> Self {
+        Harness {
+            backend: SoftwareBackend::new(width, height),
+            updaters: Vec::new(),
+            renderers: Vec::new(),
+        }
+    }
+    pub fn background(self, background: Vector4<f32>) -> Self {
+        Harness {
+            backend: self.backend.background(background),
+            ..self
+        }
+    }
+    pub fn add_updater<U>(mut self, updater: U) -> Self
+    where
+        U: Updater<SoftwareBackend, D> + 'static,
+    {
+        self.updaters.push(Box::new(updater));
+        self
+    }
+    pub fn add_renderer<R>(mut self, renderer: R) -> Self
+    where
+        R: Renderer<SoftwareBackend, D> + 'static,
+    {
+        self.renderers.push(Box::new(renderer));
+        self
+    }
+    /// Updates and renders `data` `ticks` times (at least once) and returns
+    /// the last frame.
+    ///
+    /// Every tick is rendered, not only the last one, because some renderers
+    /// keep state between frames.
+    pub fn render(&mut self, data: &mut D, ticks: usize) -> RgbaImage {
+        for _ in 0..ticks.max(1) {
+            for updater in self.updaters.iter_mut() {
+                updater.update(&mut self.backend, data);
+            }
+            for renderer in self.renderers.iter_mut() {
+                renderer.render(&mut self.backend, data);
+            }
+            self.backend.draw();
+        }
+        self.backend.framebuffer().to_image()
+    }
+}
+
+/// How far a frame can stray from its reference.
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    /// Largest distance between two pixels that still counts as the same,
+    /// as a CIE76 ΔE. Around 2.3 is the smallest difference people notice.
+    pub delta_e: f32,
+    /// Fraction of the pixels that can be different anyway, for edges
+    /// landing on the other side of a pixel centre.
+    pub differing: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            delta_e: 2.3,
+            differing: 0.002,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Comparison {
+    pub pixels: usize,
+    pub differing: usize,
+    /// The largest ΔE found.
+    pub worst: f32,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(io::Error),
+    Image(ImageError),
+    /// There is no reference to compare against.
+    Missing(PathBuf),
+    /// Update mode wrote the frame as the reference instead of comparing.
+    Updated(PathBuf),
+    Size {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// Too many pixels differ. The frame and a diff image were written to
+    /// `actual` and `diff`.
+    Mismatch {
+        comparison: Comparison,
+        actual: PathBuf,
+        diff: PathBuf,
+    },
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GoldenError::Io(ref e) => write!(f, "{}", e),
+            GoldenError::Image(ref e) => write!(f, "{}", e),
+            GoldenError::Missing(ref path) => write!(
+                f,
+                "no reference image at {}, write it with {}=1 and commit it",
+                path.display(),
+                UPDATE_VAR
+            ),
+            GoldenError::Updated(ref path) => write!(
+                f,
+                "wrote {}, check it, commit it and run again without {}",
+                path.display(),
+                UPDATE_VAR
+            ),
+            GoldenError::Size { expected, actual } => write!(
+                f,
+                "the frame is {}x{} but the reference is {}x{}",
+                actual.0, actual.1, expected.0, expected.1
+            ),
+            GoldenError::Mismatch {
+                comparison,
+                ref actual,
+                ref diff,
+            } => write!(
+                f,
+                "{} of {} pixels differ (worst ΔE {:.1}), see {} and {}",
+                comparison.differing,
+                comparison.pixels,
+                comparison.worst,
+                actual.display(),
+                diff.display()
+            ),
+        }
+    }
+}
+
+impl Error for GoldenError {}
+
+impl From<io::Error> for GoldenError {
+    fn from(error: io::Error) -> Self {
+        GoldenError::Io(error)
+    }
+}
+
+impl From<ImageError> for GoldenError {
+    fn from(error: ImageError) -> Self {
+        GoldenError::Image(error)
+    }
+}
+
+/// A frame checked against `<references>/<name>.png`, by default in
+/// `fixtures/golden` next to this file.
+///
+/// Set `UPDATE_GOLDEN=1` to write the references after an intended change.
+/// Every comparison then fails naming the file it wrote, so an update never
+/// passes as a test run.
+pub struct Golden {
+    name: String,
+    references: PathBuf,
+    diffs: PathBuf,
+    tolerance: Tolerance,
+    update: bool,
+}
+
+impl Golden {
+    pub fn new(name: &str) -> Self {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        Golden {
+            name: name.to_owned(),
+            references: root.join(file!()).with_file_name("fixtures").join("golden"),
+            diffs: root.join("target").join("golden"),
+            tolerance: Tolerance::default(),
+            update: env::var(UPDATE_VAR).is_ok(),
+        }
+    }
+    pub fn references<P: Into<PathBuf>>(self, dir: P) -> Self {
+        Golden {
+            references: dir.into(),
+            ..self
+        }
+    }
+    /// Where failing frames and their diff images go.
+    pub fn diffs<P: Into<PathBuf>>(self, dir: P) -> Self {
+        Golden {
+            diffs: dir.into(),
+            ..self
+        }
+    }
+    pub fn tolerance(self, tolerance: Tolerance) -> Self {
+        Golden { tolerance, ..self }
+    }
+    /// Whether to write the reference instead of comparing, `UPDATE_GOLDEN`
+    /// by default.
+    pub fn update(self, update: bool) -> Self {
+        Golden { update, ..self }
+    }
+    pub fn reference(&self) -> PathBuf {
+        self.references.join(format!("{}.png", self.name))
+    }
+    pub fn compare(&self, image: &RgbaImage) -> Result<Comparison, GoldenError> {
+        let path = self.reference();
+        if self.update {
+            save(image, &path)?;
+            return Err(GoldenError::Updated(path));
+        }
+        if !path.exists() {
+            return Err(GoldenError::Missing(path));
+        }
+
+        let reference = image::open(&path)?.to_rgba();
+        if reference.dimensions() != image.dimensions() {
+            return Err(GoldenError::Size {
+                expected: reference.dimensions(),
+                actual: image.dimensions(),
+            });
+        }
+
+        let mut comparison = Comparison {
+            pixels: (image.width() * image.height()) as usize,
+            differing: 0,
+            worst: 0.0,
+        };
+        let mut diff = Vec::with_capacity(reference.len());
+        for (a, b) in reference.chunks(4).zip(image.chunks(4)) {
+            let distance = delta_e(a, b);
+            comparison.worst = comparison.worst.max(distance);
+            if distance > self.tolerance.delta_e {
+                comparison.differing += 1;
+                let strength = (distance / 50.0).min(1.0);
+                diff.extend_from_slice(&[(128.0 + 127.0 * strength) as u8, 0, 0, 255]);
+            } else {
+                // The reference, faded, for context
+                let luma = (0.299 * a[0] as f32 + 0.587 * a[1] as f32 + 0.114 * a[2] as f32) / 3.0;
+                diff.extend_from_slice(&[luma as u8, luma as u8, luma as u8, 255]);
+            }
+        }
+
+        if comparison.differing as f32 > self.tolerance.differing * comparison.pixels as f32 {
+            let actual = self.diffs.join(format!("{}.png", self.name));
+            let diff_path = self.diffs.join(format!("{}.diff.png", self.name));
+            save(image, &actual)?;
+            let diff = RgbaImage::from_raw(image.width(), image.height(), diff).unwrap();
+            save(&diff, &diff_path)?;
+            return Err(GoldenError::Mismatch {
+                comparison,
+                actual,
+                diff: diff_path,
+            });
+        }
+        Ok(comparison)
+    }
+    /// Panics unless `image` matches the reference.
+    pub fn assert_matches(&self, image: &RgbaImage) {
+        if let Err(e) = self.compare(image) {
+            panic!("golden image {}: {}", self.name, e);
+        }
+    }
+}
+
+fn save(image: &RgbaImage, path: &Path) -> Result<(), GoldenError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    image.save(path)?;
+    Ok(())
+}
+
+/// CIE L*a*b* of an sRGB pixel, under D65.
+fn lab(pixel: &[u8]) -> [f32; 3] {
+    let linear = |c: u8| {
+        let c = c as f32 / 255.0;
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let (r, g, b) = (linear(pixel[0]), linear(pixel[1]), linear(pixel[2]));
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// How different two RGBA pixels look: the ΔE between their colours, plus
+/// the difference in alpha on the same 0 to 100 scale.
+pub fn delta_e(a: &[u8], b: &[u8]) -> f32 {
+    let (la, lb) = (lab(a), lab(b));
+    let colour = ((la[0] - lb[0]).powi(2) + (la[1] - lb[1]).powi(2) + (la[2] - lb[2]).powi(2)).sqrt();
+    colour + (a[3] as f32 - b[3] as f32).abs() / 255.0 * 100.0
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::golden::{delta_e, FixedClockUpdater, Golden, GoldenError, Harness, Script,
+                         Tolerance};
+    use drafts::software_backend::MeshBackend;
+    use image::RgbaImage;
+    use mursten::{Data, Renderer};
+    use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
+    use mursten_blocks::time::{Clock, OnTick, Tick};
+    use nalgebra::*;
+    use std::env;
+    use std::fs;
+
+    fn solid(rgba: [u8; 4]) -> RgbaImage {
+        RgbaImage::from_raw(10, 10, rgba.iter().cloned().cycle().take(400).collect()).unwrap()
+    }
+
+    #[test]
+    fn perceptual_distance() {
+        assert_eq!(delta_e(&[40, 80, 120, 255], &[40, 80, 120, 255]), 0.0);
+        assert!((delta_e(&[0, 0, 0, 255], &[255, 255, 255, 255]) - 100.0).abs() < 0.1);
+        // One step in a dark blue can't be seen, the same step in alpha can
+        assert!(delta_e(&[0, 0, 60, 255], &[0, 0, 61, 255]) < 1.0);
+        assert!(delta_e(&[0, 0, 60, 255], &[0, 0, 60, 200]) > 20.0);
+        // Green changes show more than blue ones
+        assert!(delta_e(&[0, 128, 0, 255], &[0, 148, 0, 255]) > delta_e(&[0, 0, 128, 255], &[0, 0, 148, 255]));
+    }
+
+    #[test]
+    fn compares_against_references() {
+        let dir = env::temp_dir().join(format!("golden-test-{}", ::std::process::id()));
+        let golden = Golden::new("square")
+            .references(dir.join("references"))
+            .diffs(dir.join("diffs"))
+            .update(false);
+
+        // A missing reference fails without being written
+        match golden.compare(&solid([20, 40, 200, 255])) {
+            Err(GoldenError::Missing(ref path)) => assert!(!path.exists()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Writing one fails too, so an update can't pass as a run
+        let updating = Golden::new("square")
+            .references(dir.join("references"))
+            .update(true);
+        match updating.compare(&solid([20, 40, 200, 255])) {
+            Err(GoldenError::Updated(ref path)) => assert!(path.exists()),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let same = golden.compare(&solid([21, 40, 199, 255])).unwrap();
+        assert_eq!(same.differing, 0);
+        assert!(same.worst > 0.0);
+
+        let mut changed = solid([20, 40, 200, 255]);
+        for x in 0..5 {
+            changed.put_pixel(x, 0, *solid([250, 40, 20, 255]).get_pixel(0, 0));
+        }
+        match golden.compare(&changed) {
+            Err(GoldenError::Mismatch { comparison, ref diff, .. }) => {
+                assert_eq!(comparison.differing, 5);
+                assert_eq!(comparison.pixels, 100);
+                assert!(diff.exists());
+                assert!(dir.join("diffs/square.png").exists());
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Unless that's few enough
+        let lenient = Golden::new("square")
+            .references(dir.join("references"))
+            .update(false)
+            .tolerance(Tolerance {
+                differing: 0.05,
+                ..Tolerance::default()
+            });
+        assert_eq!(lenient.compare(&changed).unwrap().differing, 5);
+
+        match golden.compare(&RgbaImage::new(4, 4)) {
+            Err(GoldenError::Size { expected: (10, 10), actual: (4, 4) }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Counter {
+        clock: Clock,
+        ticks: usize,
+        lit: bool,
+    }
+
+    impl Data for Counter {}
+
+    impl OnTick for Counter {
+        fn on_tick(&mut self, tick: Tick) {
+            self.clock += tick;
+            self.ticks += 1;
+        }
+    }
+
+    struct Square;
+
+    impl<B: MeshBackend> Renderer<B, Counter> for Square {
+        fn render(&mut self, backend: &mut B, counter: &Counter) {
+            let c = if counter.lit { 1.0 } else { 0.2 };
+            let v = |x, y| Vertex::at(Point3::new(x, y, 0.0)).color(c, c, c, 1.0);
+            backend.queue_render(Mesh {
+                triangles: vec![
+                    Triangle::new(v(-1.0, -1.0), v(1.0, -1.0), v(-1.0, 1.0)),
+                    Triangle::new(v(-1.0, 1.0), v(1.0, -1.0), v(1.0, 1.0)),
+                ],
+                transform: Transform3::identity() * Translation3::new(0.0, 0.0, -2.0),
+            });
+        }
+    }
+
+    #[test]
+    fn steps_with_fixed_ticks_and_scripts() {
+        let mut counter = Counter {
+            clock: Clock::new(),
+            ticks: 0,
+            lit: false,
+        };
+        let mut harness = Harness::new(8, 8)
+            .add_updater(FixedClockUpdater::default())
+            .add_updater(Script::new().at(3, |c: &mut Counter| c.lit = true))
+            .add_renderer(Square);
+
+        let image = harness.render(&mut counter, 3);
+        assert_eq!(counter.ticks, 3);
+        assert!(!counter.lit);
+        assert_eq!(image.get_pixel(4, 4), solid([51, 51, 51, 255]).get_pixel(0, 0));
+
+        let image = harness.render(&mut counter, 57);
+        assert_eq!(counter.ticks, 60);
+        assert!((counter.clock.time_in_sec() - 1.0).abs() < 0.001);
+        assert_eq!(image.get_pixel(4, 4), solid([255, 255, 255, 255]).get_pixel(0, 0));
+    }
+}
@@ -0,0 +1,350 @@
+use drafts::markov::seeded_rng;
+use rand::{Rng, StdRng};
+use serde::de::{self, Deserialize, Deserializer};
+
+/// What a tile of a layout is, from the lowest to the highest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Terrain {
+    Water,
+    Path,
+    Ground,
+    Hill,
+    Ridge,
+}
+
+const TERRAINS: [Terrain; 5] = [
+    Terrain::Water,
+    Terrain::Path,
+    Terrain::Ground,
+    Terrain::Hill,
+    Terrain::Ridge,
+];
+
+impl Terrain {
+    fn level(self) -> usize {
+        self as usize
+    }
+    pub fn height(self) -> f32 {
+        match self {
+            Terrain::Water => -0.1,
+            Terrain::Path => 0.0,
+            Terrain::Ground => 0.15,
+            Terrain::Hill => 0.35,
+            Terrain::Ridge => 0.6,
+        }
+    }
+    pub fn is_walkable(self) -> bool {
+        self != Terrain::Water
+    }
+    fn symbol(self) -> char {
+        match self {
+            Terrain::Water => '~',
+            Terrain::Path => '=',
+            Terrain::Ground => '.',
+            Terrain::Hill => 'n',
+            Terrain::Ridge => 'A',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Tile {
+    pub terrain: Terrain,
+    pub height: f32,
+    /// Whether the tile sits above the base height of its terrain.
+    pub bright: bool,
+}
+
+/// A `width` by `depth` grid of tiles, stored row after row.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Layout {
+    width: usize,
+    depth: usize,
+    tiles: Vec<Tile>,
+}
+
+impl Layout {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+    pub fn get(&self, x: usize, z: usize) -> &Tile {
+        &self.tiles[z * self.width + x]
+    }
+    /// Every tile, row after row.
+    pub fn tiles(&self) -> &[Tile] {
+        &self.tiles
+    }
+    /// One character per tile, one line per row.
+    pub fn to_text(&self) -> String {
+        self.tiles
+            .chunks(self.width)
+            .map(|row| row.iter().map(|t| t.terrain.symbol()).collect::<String>() + "\n")
+            .collect()
+    }
+}
+
+impl<'de> Deserialize<'de> for Layout {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Grid {
+            width: usize,
+            depth: usize,
+            tiles: Vec<Tile>,
+        }
+        let Grid { width, depth, tiles } = Grid::deserialize(deserializer)?;
+        // Every tile has to be there for `get` to find it
+        if width.checked_mul(depth) != Some(tiles.len()) {
+            return Err(de::Error::custom(format!(
+                "a {} by {} layout can't have {} tiles",
+                width,
+                depth,
+                tiles.len()
+            )));
+        }
+        Ok(Layout {
+            width,
+            depth,
+            tiles,
+        })
+    }
+}
+
+/// Generates layouts as a Markov random field over terrains: every tile
+/// depends on its four neighbours, so heights come in patches and ridges
+/// instead of noise.
+///
+/// Paths are walked first, west to east, as a chain of steps that mostly keep
+/// going the same way, and the rest of the tiles are then sampled around them.
+#[derive(Clone, Debug)]
+pub struct LayoutGenerator {
+    width: usize,
+    depth: usize,
+    coupling: f64,
+    prior: [f64; 5],
+    sweeps: usize,
+    paths: usize,
+    turn: f64,
+    jitter: f32,
+}
+
+impl LayoutGenerator {
+    pub fn new(width: usize, depth: usize) -> Self {
+        LayoutGenerator {
+            width,
+            depth,
+            coupling: 1.2,
+            prior: [0.15, 0.0, 0.45, 0.25, 0.15],
+            sweeps: 20,
+            paths: 1,
+            turn: 0.3,
+            jitter: 0.05,
+        }
+    }
+    /// How strongly a tile wants to be like its neighbours. 0 gives
+    /// independent tiles.
+    pub fn coupling(self, coupling: f64) -> Self {
+        LayoutGenerator { coupling, ..self }
+    }
+    /// Relative frequency of each terrain when neighbours don't matter.
+    /// Paths only appear where they are walked.
+    pub fn prior(self, water: f64, ground: f64, hill: f64, ridge: f64) -> Self {
+        LayoutGenerator {
+            prior: [water, 0.0, ground, hill, ridge],
+            ..self
+        }
+    }
+    /// Rounds of Gibbs sampling over the whole grid.
+    pub fn sweeps(self, sweeps: usize) -> Self {
+        LayoutGenerator { sweeps, ..self }
+    }
+    pub fn paths(self, paths: usize) -> Self {
+        LayoutGenerator { paths, ..self }
+    }
+    /// Probability of a path changing row at each step.
+    pub fn turn(self, turn: f64) -> Self {
+        LayoutGenerator {
+            turn: turn.max(0.0).min(1.0),
+            ..self
+        }
+    }
+    pub fn generate(&self, seed: u64) -> Layout {
+        let mut rng = seeded_rng(seed);
+        let mut levels: Vec<Option<usize>> = vec![None; self.width * self.depth];
+        let mut fixed = vec![false; levels.len()];
+        if self.width > 0 && self.depth > 0 {
+            for _ in 0..self.paths {
+                for i in self.walk_path(&mut rng) {
+                    levels[i] = Some(Terrain::Path.level());
+                    fixed[i] = true;
+                }
+            }
+        }
+        for _ in 0..self.sweeps.max(1) {
+            for i in 0..levels.len() {
+                if !fixed[i] {
+                    levels[i] = Some(self.sample_level(&levels, i, &mut rng));
+                }
+            }
+        }
+        let tiles = levels
+            .into_iter()
+            .map(|level| {
+                let terrain = TERRAINS[level.unwrap()];
+                let offset = (rng.gen::<f32>() * 2.0 - 1.0) * self.jitter;
+                Tile {
+                    terrain,
+                    height: terrain.height() + offset,
+                    bright: offset > 0.0,
+                }
+            })
+            .collect();
+        Layout {
+            width: self.width,
+            depth: self.depth,
+            tiles,
+        }
+    }
+    /// The tiles of a path from the west edge to the east edge, moving to a
+    /// neighbouring row with probability `turn` and keeping the last turn's
+    /// direction when it does, unless the border is in the way.
+    fn walk_path(&self, rng: &mut StdRng) -> Vec<usize> {
+        let mut z = rng.gen_range(0, self.depth);
+        let mut direction: isize = if rng.gen() { 1 } else { -1 };
+        let mut path = Vec::new();
+        for x in 0..self.width {
+            path.push(z * self.width + x);
+            if x + 1 < self.width && rng.gen::<f64>() < self.turn {
+                if rng.gen::<f64>() < 0.2 {
+                    direction = -direction;
+                }
+                let next = z as isize + direction;
+                if next < 0 || next >= self.depth as isize {
+                    direction = -direction;
+                } else {
+                    // Turning takes a tile of its own so the path stays walkable
+                    z = next as usize;
+                    path.push(z * self.width + x);
+                }
+            }
+        }
+        path
+    }
+    fn sample_level(&self, levels: &[Option<usize>], i: usize, rng: &mut StdRng) -> usize {
+        let (x, z) = (i % self.width, i / self.width);
+        let mut neighbours = Vec::with_capacity(4);
+        if x > 0 {
+            neighbours.push(levels[i - 1]);
+        }
+        if x + 1 < self.width {
+            neighbours.push(levels[i + 1]);
+        }
+        if z > 0 {
+            neighbours.push(levels[i - self.width]);
+        }
+        if z + 1 < self.depth {
+            neighbours.push(levels[i + self.width]);
+        }
+        let weights: Vec<f64> = (0..TERRAINS.len())
+            .map(|level| {
+                let disagreement: f64 = neighbours
+                    .iter()
+                    .filter_map(|&n| n)
+                    .map(|n| (n as f64 - level as f64).abs())
+                    .sum();
+                self.prior[level] * (-self.coupling * disagreement).exp()
+            })
+            .collect();
+        let mut target = rng.gen::<f64>() * weights.iter().sum::<f64>();
+        for (level, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                return level;
+            }
+            target -= weight;
+        }
+        Terrain::Ground.level()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::layout::{Layout, LayoutGenerator, Terrain};
+    use serde_json;
+
+    fn neighbour_difference(layout: &Layout) -> f32 {
+        let mut total = 0.0;
+        let mut pairs = 0;
+        for z in 0..layout.depth() {
+            for x in 1..layout.width() {
+                total += (layout.get(x, z).height - layout.get(x - 1, z).height).abs();
+                pairs += 1;
+            }
+        }
+        total / pairs as f32
+    }
+
+    #[test]
+    fn seeded_layouts() {
+        let generator = LayoutGenerator::new(20, 20);
+        let layout = generator.generate(42);
+        assert_eq!(layout.tiles().len(), 400);
+        assert_eq!(layout, generator.generate(42));
+        assert_ne!(layout, generator.generate(43));
+        assert_eq!(layout.to_text().lines().count(), 20);
+    }
+
+    #[test]
+    fn neighbours_are_correlated() {
+        let correlated = LayoutGenerator::new(20, 20).generate(7);
+        let independent = LayoutGenerator::new(20, 20).coupling(0.0).generate(7);
+        assert!(neighbour_difference(&correlated) < neighbour_difference(&independent) * 0.7);
+    }
+
+    #[test]
+    fn paths_cross_the_grid() {
+        for seed in 0..10 {
+            let layout = LayoutGenerator::new(20, 20).paths(1).turn(0.5).generate(seed);
+            // Every column has a path tile next to one in the previous column
+            let mut reached: Vec<usize> = (0..20)
+                .filter(|&z| layout.get(0, z).terrain == Terrain::Path)
+                .collect();
+            for x in 1..20 {
+                let column: Vec<usize> = (0..20)
+                    .filter(|&z| layout.get(x, z).terrain == Terrain::Path)
+                    .collect();
+                reached = column
+                    .iter()
+                    .cloned()
+                    .filter(|z| reached.contains(z))
+                    .collect();
+                assert!(!reached.is_empty(), "seed {}:\n{}", seed, layout.to_text());
+                // Follow the path along the column
+                let mut spread = true;
+                while spread {
+                    spread = false;
+                    for &z in &column {
+                        if !reached.contains(&z)
+                            && reached.iter().any(|&r| r + 1 == z || z + 1 == r)
+                        {
+                            reached.push(z);
+                            spread = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn serialise_layout() {
+        let layout = LayoutGenerator::new(4, 3).generate(1);
+        let json = serde_json::to_string(&layout).unwrap();
+        let back: Layout = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, layout);
+
+        let wide = json.replacen("\"width\":4", "\"width\":5", 1);
+        assert!(serde_json::from_str::<Layout>(&wide).is_err());
+    }
+}
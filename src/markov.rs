@@ -1,6 +1,53 @@
-use markov::Chain;
+use bincode;
+use drafts::markov_analysis::{Analysis, State};
+use rand::{self, Rng, SeedableRng, StdRng};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json;
+use std::collections::BTreeMap;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+
+const USAGE: &str = "usage:
+    markov train [--words | --chars] [--order N] --output MODEL [CORPUS...]
+    markov generate MODEL [--count N] [--seed TOKEN] [--random-seed N]
+                          [--temperature T] [--top-k K]
+    markov analyse MODEL [--matrix CSV]
+
+Corpora are read line by line, from stdin when no file is given. Models ending
+in .json are stored as JSON, anything else as bincode.";
 
 pub fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(|a| a.as_str()) {
+        None => {
+            demo();
+            Ok(())
+        }
+        Some("train") => train(&args[1..]),
+        Some("generate") => generate(&args[1..]),
+        Some("analyse") => analyse(&args[1..]),
+        Some(_) => Err(CliError::Usage),
+    };
+    match result {
+        Ok(()) => (),
+        Err(CliError::Usage) => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+        Err(e) => {
+            eprintln!("markov: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn demo() {
     let mut chain = Chain::of_order(2);
     chain.feed(vec![1u8, 2, 3, 5]).feed(vec![3u8, 9, 2]);
     println!("{:?}", chain.generate());
@@ -9,3 +56,590 @@ pub fn main() {
     }
 }
 
+fn train(args: &[String]) -> Result<(), CliError> {
+    let mut chars = false;
+    let mut order = 2;
+    let mut output = None;
+    let mut corpora = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--words" => chars = false,
+            "--chars" => chars = true,
+            "--order" => order = parse_value(args.next())?,
+            "--output" | "-o" => output = Some(args.next().ok_or(CliError::Usage)?),
+            _ if arg.starts_with('-') => return Err(CliError::Usage),
+            _ => corpora.push(arg),
+        }
+    }
+    let output = output.ok_or(CliError::Usage)?;
+
+    let mut model = if chars {
+        Model::chars(order)
+    } else {
+        Model::words(order)
+    };
+    if corpora.is_empty() {
+        let stdin = io::stdin();
+        model.train(stdin.lock())?;
+    }
+    for corpus in corpora {
+        model.train(BufReader::new(File::open(corpus)?))?;
+    }
+    model.save(output)?;
+    Ok(())
+}
+
+fn generate(args: &[String]) -> Result<(), CliError> {
+    let mut path = None;
+    let mut count: usize = 1;
+    let mut seed = None;
+    let mut random_seed = rand::thread_rng().gen();
+    let mut sampler = Sampler::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--count" | "-n" => count = parse_value(args.next())?,
+            "--seed" => seed = Some(args.next().ok_or(CliError::Usage)?),
+            "--random-seed" => random_seed = parse_value(args.next())?,
+            "--temperature" => sampler = sampler.temperature(parse_value(args.next())?),
+            "--top-k" => sampler = sampler.top_k(parse_value(args.next())?),
+            _ if arg.starts_with('-') => return Err(CliError::Usage),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(CliError::Usage),
+        }
+    }
+    let model = Model::load(path.ok_or(CliError::Usage)?)?;
+    let mut rng = seeded_rng(random_seed);
+    for _ in 0..count {
+        println!("{}", model.generate(seed.map(|s| s.as_str()), &sampler, &mut rng));
+    }
+    Ok(())
+}
+
+fn analyse(args: &[String]) -> Result<(), CliError> {
+    let mut path = None;
+    let mut matrix = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--matrix" => matrix = Some(args.next().ok_or(CliError::Usage)?),
+            _ if arg.starts_with('-') => return Err(CliError::Usage),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(CliError::Usage),
+        }
+    }
+    let model = Model::load(path.ok_or(CliError::Usage)?)?;
+    let csv = match model {
+        Model::Words(ref chain) => report(&Analysis::of(chain)),
+        Model::Chars(ref chain) => report(&Analysis::of(chain)),
+    };
+    if let Some(matrix) = matrix {
+        File::create(matrix)?.write_all(csv.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Prints what the analysis found and returns the matrix as CSV.
+fn report<T: fmt::Debug>(analysis: &Analysis<T>) -> String {
+    let name = |i: usize| match analysis.states()[i] {
+        State::Context(ref tokens) => format!("{:?}", tokens),
+        State::End => "End".to_owned(),
+    };
+    println!("states: {}", analysis.states().len());
+    println!("entropy rate: {:.3} bits per token", analysis.entropy_rate());
+    println!("expected length: {:.2} tokens", analysis.expected_length());
+    for i in analysis.absorbing_states() {
+        println!("absorbing: {}", name(i));
+    }
+    for i in analysis.dead_ends() {
+        println!("dead end, generation stops early at: {}", name(i));
+    }
+    for i in analysis.trapped_states() {
+        println!("may never end after: {}", name(i));
+    }
+    analysis.to_csv()
+}
+
+fn parse_value<T: FromStr>(arg: Option<&String>) -> Result<T, CliError> {
+    arg.and_then(|a| a.parse().ok()).ok_or(CliError::Usage)
+}
+
+/// A token of a fed sequence, plus the markers around it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Token<T> {
+    Start,
+    Word(T),
+    Stop,
+}
+
+/// A Markov chain that counts, for every context of up to `order` tokens, how
+/// many times each token followed it.
+///
+/// When generating, contexts that were never seen back off to their longest
+/// seen suffix, down to the plain token frequencies.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chain<T>
+where
+    T: Ord + Clone,
+{
+    order: usize,
+    transitions: BTreeMap<Vec<Token<T>>, BTreeMap<Token<T>, usize>>,
+}
+
+impl<T> Chain<T>
+where
+    T: Ord + Clone,
+{
+    pub fn of_order(order: usize) -> Self {
+        Chain {
+            order,
+            transitions: BTreeMap::new(),
+        }
+    }
+    pub fn order(&self) -> usize {
+        self.order
+    }
+    pub fn is_empty(&self) -> bool {
+        self.transitions.is_empty()
+    }
+    pub fn feed<S: AsRef<[T]>>(&mut self, tokens: S) -> &mut Self {
+        let tokens = tokens.as_ref();
+        if tokens.is_empty() {
+            return self;
+        }
+        let mut padded = vec![Token::Start; self.order];
+        padded.extend(tokens.iter().cloned().map(Token::Word));
+        padded.push(Token::Stop);
+        for i in self.order..padded.len() {
+            for k in 0..=self.order {
+                *self.transitions
+                    .entry(padded[i - k..i].to_vec())
+                    .or_insert_with(BTreeMap::new)
+                    .entry(padded[i].clone())
+                    .or_insert(0) += 1;
+            }
+        }
+        self
+    }
+    /// Every context seen while feeding, with the tokens that followed it and
+    /// how many times.
+    pub fn transitions(&self) -> &BTreeMap<Vec<Token<T>>, BTreeMap<Token<T>, usize>> {
+        &self.transitions
+    }
+    /// How many times `next` followed exactly `context`.
+    pub fn count(&self, context: &[Token<T>], next: &Token<T>) -> usize {
+        self.transitions
+            .get(context)
+            .and_then(|followers| followers.get(next))
+            .cloned()
+            .unwrap_or(0)
+    }
+    /// The tokens seen after the longest seen suffix of `context`, with their counts.
+    pub fn followers(&self, context: &[Token<T>]) -> Option<&BTreeMap<Token<T>, usize>> {
+        let start = context.len().saturating_sub(self.order);
+        (start..=context.len())
+            .filter_map(|i| self.transitions.get(&context[i..]))
+            .next()
+    }
+    /// `P(next | context)`, backing off like generation does.
+    pub fn probability(&self, context: &[Token<T>], next: &Token<T>) -> f64 {
+        match self.followers(context) {
+            Some(followers) => {
+                let total: usize = followers.values().sum();
+                followers.get(next).cloned().unwrap_or(0) as f64 / total as f64
+            }
+            None => 0.0,
+        }
+    }
+    pub fn generate(&self) -> Vec<T> {
+        self.generate_with(&Sampler::default(), &mut rand::thread_rng())
+    }
+    pub fn generate_from_token(&self, token: T) -> Vec<T> {
+        self.continue_with(vec![token], &Sampler::default(), &mut rand::thread_rng())
+    }
+    pub fn generate_with<R: Rng>(&self, sampler: &Sampler, rng: &mut R) -> Vec<T> {
+        self.continue_with(Vec::new(), sampler, rng)
+    }
+    /// Generates the rest of a sequence that starts with `prefix`.
+    pub fn continue_with<R: Rng>(&self, prefix: Vec<T>, sampler: &Sampler, rng: &mut R) -> Vec<T> {
+        let mut context = vec![Token::Start; self.order];
+        context.extend(prefix.iter().cloned().map(Token::Word));
+        let mut sequence = prefix;
+        while sequence.len() < sampler.max_length {
+            let next = match self.followers(&context) {
+                Some(followers) => sampler.sample(followers, rng),
+                None => break,
+            };
+            match next {
+                Token::Word(token) => {
+                    sequence.push(token.clone());
+                    context.push(Token::Word(token));
+                }
+                Token::Start | Token::Stop => break,
+            }
+        }
+        sequence
+    }
+    pub fn iter_for<'a>(&'a self, count: usize) -> impl Iterator<Item = Vec<T>> + 'a {
+        (0..count).map(move |_| self.generate())
+    }
+}
+
+impl Chain<String> {
+    pub fn feed_str(&mut self, string: &str) -> &mut Self {
+        self.feed(string.split_whitespace().map(|w| w.to_owned()).collect::<Vec<_>>())
+    }
+    pub fn generate_str(&self) -> String {
+        self.generate().join(" ")
+    }
+    pub fn generate_str_from_token(&self, token: &str) -> String {
+        self.generate_from_token(token.to_owned()).join(" ")
+    }
+}
+
+impl<T> Serialize for Chain<T>
+where
+    T: Ord + Clone + Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Contexts aren't strings, so they can't be map keys in every format.
+        let transitions: Vec<(&Vec<Token<T>>, Vec<(&Token<T>, &usize)>)> = self
+            .transitions
+            .iter()
+            .map(|(context, followers)| (context, followers.iter().collect()))
+            .collect();
+        (self.order, transitions).serialize(serializer)
+    }
+}
+
+impl<'de, T> Deserialize<'de> for Chain<T>
+where
+    T: Ord + Clone + Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (order, transitions): (usize, Vec<(Vec<Token<T>>, Vec<(Token<T>, usize)>)>) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(Chain {
+            order,
+            transitions: transitions
+                .into_iter()
+                .map(|(context, followers)| (context, followers.into_iter().collect()))
+                .collect(),
+        })
+    }
+}
+
+/// How the next token is picked among the ones seen after a context.
+#[derive(Clone, Debug)]
+pub struct Sampler {
+    temperature: f64,
+    top_k: Option<usize>,
+    max_length: usize,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Sampler {
+            temperature: 1.0,
+            top_k: None,
+            max_length: 1000,
+        }
+    }
+}
+
+impl Sampler {
+    /// Below 1 favours the most frequent tokens, above 1 flattens the
+    /// distribution. 0 always picks the most frequent one.
+    pub fn temperature(self, temperature: f64) -> Self {
+        Sampler { temperature, ..self }
+    }
+    /// Only considers the `k` most frequent tokens.
+    pub fn top_k(self, k: usize) -> Self {
+        Sampler {
+            top_k: Some(k.max(1)),
+            ..self
+        }
+    }
+    /// Cuts sequences that don't stop by themselves.
+    pub fn max_length(self, max_length: usize) -> Self {
+        Sampler { max_length, ..self }
+    }
+    pub fn sample<T, R>(&self, followers: &BTreeMap<Token<T>, usize>, rng: &mut R) -> Token<T>
+    where
+        T: Ord + Clone,
+        R: Rng,
+    {
+        let mut candidates: Vec<(&Token<T>, usize)> =
+            followers.iter().map(|(token, &count)| (token, count)).collect();
+        candidates.sort_by(|a, b| b.1.cmp(&a.1));
+        if let Some(k) = self.top_k {
+            candidates.truncate(k);
+        }
+        if self.temperature <= 0.0 {
+            return candidates[0].0.clone();
+        }
+        let max = candidates[0].1 as f64;
+        let weights: Vec<f64> = candidates
+            .iter()
+            .map(|&(_, count)| (count as f64 / max).powf(1.0 / self.temperature))
+            .collect();
+        let mut target = rng.gen::<f64>() * weights.iter().sum::<f64>();
+        for (&(token, _), weight) in candidates.iter().zip(weights) {
+            if target < weight {
+                return token.clone();
+            }
+            target -= weight;
+        }
+        candidates[candidates.len() - 1].0.clone()
+    }
+}
+
+/// A reproducible random number generator for `generate_with`.
+pub fn seeded_rng(seed: u64) -> StdRng {
+    StdRng::from_seed(&[seed as usize][..])
+}
+
+/// A chain trained either on whole words or on single characters.
+#[derive(Serialize, Deserialize)]
+pub enum Model {
+    Words(Chain<String>),
+    Chars(Chain<char>),
+}
+
+impl Model {
+    pub fn words(order: usize) -> Self {
+        Model::Words(Chain::of_order(order))
+    }
+    pub fn chars(order: usize) -> Self {
+        Model::Chars(Chain::of_order(order))
+    }
+    /// Feeds every non empty line of the corpus as a sequence of its own.
+    pub fn train<R: BufRead>(&mut self, corpus: R) -> io::Result<()> {
+        for line in corpus.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            match *self {
+                Model::Words(ref mut chain) => {
+                    chain.feed_str(line);
+                }
+                Model::Chars(ref mut chain) => {
+                    chain.feed(line.chars().collect::<Vec<_>>());
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Generates a sentence, starting from `seed` if given: a word for word
+    /// models, a character for character models.
+    pub fn generate<R: Rng>(&self, seed: Option<&str>, sampler: &Sampler, rng: &mut R) -> String {
+        match *self {
+            Model::Words(ref chain) => {
+                let prefix = seed.map(|word| vec![word.to_owned()]).unwrap_or_default();
+                chain.continue_with(prefix, sampler, rng).join(" ")
+            }
+            Model::Chars(ref chain) => {
+                let prefix = seed.and_then(|s| s.chars().next()).into_iter().collect();
+                chain.continue_with(prefix, sampler, rng).into_iter().collect()
+            }
+        }
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ModelError> {
+        let json = is_json(path.as_ref());
+        let file = BufWriter::new(File::create(path)?);
+        if json {
+            serde_json::to_writer(file, self)?;
+        } else {
+            bincode::serialize_into(file, self)?;
+        }
+        Ok(())
+    }
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Model, ModelError> {
+        let json = is_json(path.as_ref());
+        let file = BufReader::new(File::open(path)?);
+        if json {
+            Ok(serde_json::from_reader(file)?)
+        } else {
+            Ok(bincode::deserialize_from(file)?)
+        }
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension().map_or(false, |ext| ext == "json")
+}
+
+#[derive(Debug)]
+pub enum ModelError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for ModelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ModelError::Io(ref e) => write!(f, "{}", e),
+            ModelError::Json(ref e) => write!(f, "invalid JSON model: {}", e),
+            ModelError::Bincode(ref e) => write!(f, "invalid bincode model: {}", e),
+        }
+    }
+}
+
+impl Error for ModelError {}
+
+impl From<io::Error> for ModelError {
+    fn from(e: io::Error) -> Self {
+        ModelError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ModelError {
+    fn from(e: serde_json::Error) -> Self {
+        ModelError::Json(e)
+    }
+}
+
+impl From<bincode::Error> for ModelError {
+    fn from(e: bincode::Error) -> Self {
+        ModelError::Bincode(e)
+    }
+}
+
+#[derive(Debug)]
+enum CliError {
+    Usage,
+    Model(ModelError),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage => write!(f, "{}", USAGE),
+            CliError::Model(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<ModelError> for CliError {
+    fn from(e: ModelError) -> Self {
+        CliError::Model(e)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Model(ModelError::Io(e))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::markov::{seeded_rng, Chain, Model, Sampler, Token};
+    use std::env;
+    use std::fs;
+
+    const CORPUS: &str = "the cat sat on the mat\n\nthe dog sat on the log\n";
+
+    #[test]
+    fn word_model_round_trip() {
+        let mut model = Model::words(1);
+        model.train(CORPUS.as_bytes()).unwrap();
+
+        for extension in &["json", "bin"] {
+            let name = format!("markov-words-{}.{}", ::std::process::id(), extension);
+            let path = env::temp_dir().join(name);
+            model.save(&path).unwrap();
+            let loaded = Model::load(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            let mut rng = seeded_rng(1);
+            for _ in 0..20 {
+                let sentence = loaded.generate(Some("dog"), &Sampler::default(), &mut rng);
+                assert!(sentence.contains("sat on the"), "{}", sentence);
+            }
+        }
+    }
+
+    #[test]
+    fn char_model_only_uses_seen_characters() {
+        let mut model = Model::chars(2);
+        model.train("pedro\nhomar\ncarlos\n".as_bytes()).unwrap();
+        let mut rng = seeded_rng(2);
+        for _ in 0..20 {
+            let name = model.generate(None, &Sampler::default(), &mut rng);
+            assert!(!name.is_empty());
+            assert!(name.chars().all(|c| "pedrohmacls".contains(c)), "{}", name);
+        }
+    }
+
+    fn demo_chain() -> Chain<u8> {
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![1u8, 2, 3, 5]).feed(vec![3u8, 9, 2]);
+        chain
+    }
+
+    #[test]
+    fn transition_counts() {
+        use self::Token::*;
+        let chain = demo_chain();
+
+        assert_eq!(chain.count(&[Start, Start], &Word(1)), 1);
+        assert_eq!(chain.count(&[Start, Start], &Word(3)), 1);
+        assert_eq!(chain.count(&[Start, Word(1)], &Word(2)), 1);
+        assert_eq!(chain.count(&[Word(2), Word(3)], &Word(5)), 1);
+        assert_eq!(chain.count(&[Word(9), Word(2)], &Stop), 1);
+        assert_eq!(chain.count(&[Word(2), Word(3)], &Stop), 0);
+
+        assert_eq!(chain.count(&[Word(2)], &Word(3)), 1);
+        assert_eq!(chain.count(&[Word(2)], &Stop), 1);
+        assert_eq!(chain.count(&[Word(3)], &Word(5)), 1);
+        assert_eq!(chain.count(&[Word(3)], &Word(9)), 1);
+
+        assert_eq!(chain.count(&[], &Word(2)), 2);
+        assert_eq!(chain.count(&[], &Stop), 2);
+    }
+
+    #[test]
+    fn probability_backs_off() {
+        use self::Token::*;
+        let chain = demo_chain();
+
+        assert_eq!(chain.probability(&[Word(2), Word(3)], &Word(5)), 1.0);
+        assert_eq!(chain.probability(&[Start, Start], &Word(3)), 0.5);
+        // Unseen pair, falls back to what followed a 3
+        assert_eq!(chain.probability(&[Word(7), Word(3)], &Word(9)), 0.5);
+        // Unseen token, falls back to the token frequencies
+        assert_eq!(chain.probability(&[Word(7), Word(7)], &Stop), 2.0 / 9.0);
+    }
+
+    #[test]
+    fn sampling() {
+        let chain = demo_chain();
+
+        let greedy = Sampler::default().temperature(0.0);
+        assert_eq!(chain.generate_with(&greedy, &mut seeded_rng(3)), vec![1, 2, 3, 5]);
+        let top_1 = Sampler::default().top_k(1);
+        assert_eq!(chain.generate_with(&top_1, &mut seeded_rng(4)), vec![1, 2, 3, 5]);
+
+        let sampler = Sampler::default().temperature(1.5);
+        let a: Vec<_> = (0..10)
+            .map(|_| chain.generate_with(&sampler, &mut seeded_rng(5)))
+            .collect();
+        let b: Vec<_> = (0..10)
+            .map(|_| chain.generate_with(&sampler, &mut seeded_rng(5)))
+            .collect();
+        assert_eq!(a, b);
+
+        let mut rng = seeded_rng(6);
+        for _ in 0..50 {
+            let sequence = chain.generate_with(&sampler, &mut rng);
+            assert!(sequence == vec![1, 2, 3, 5] || sequence == vec![3, 9, 2], "{:?}", sequence);
+        }
+        let short = Sampler::default().max_length(2);
+        assert_eq!(chain.generate_with(&short, &mut seeded_rng(7)).len(), 2);
+    }
+}
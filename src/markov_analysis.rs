@@ -0,0 +1,379 @@
+use drafts::markov::{Chain, Token};
+use std::collections::{BTreeMap, VecDeque};
+use std::f64;
+use std::fmt::Debug;
+
+/// A state of the generation process: the last `order` tokens, or the end of
+/// a sequence.
+#[derive(Clone, Debug, PartialEq)]
+pub enum State<T> {
+    Context(Vec<Token<T>>),
+    End,
+}
+
+/// The transition matrix of a chain seen as a process that generates
+/// sequences back to back: once a sequence ends, the next one starts.
+///
+/// A context is only ever followed by a few tokens, so the matrix is kept as
+/// sparse rows and everything works in time and memory proportional to the
+/// number of transitions rather than the square of the number of states.
+pub struct Analysis<T> {
+    states: Vec<State<T>>,
+    start: usize,
+    end: usize,
+    /// `(j, p)` for every `p > 0` of going from state `i` to state `j`,
+    /// ordered by `j`.
+    rows: Vec<Vec<(usize, f64)>>,
+}
+
+impl<T> Analysis<T>
+where
+    T: Ord + Clone,
+{
+    pub fn of(chain: &Chain<T>) -> Self {
+        let start_context = vec![Token::Start; chain.order()];
+        let mut contexts: Vec<Vec<Token<T>>> = chain
+            .transitions()
+            .keys()
+            .filter(|context| context.len() == chain.order())
+            .cloned()
+            .collect();
+        if !contexts.contains(&start_context) {
+            contexts.insert(0, start_context.clone());
+        }
+        let index: BTreeMap<Vec<Token<T>>, usize> = contexts
+            .iter()
+            .cloned()
+            .enumerate()
+            .map(|(i, context)| (context, i))
+            .collect();
+        let end = contexts.len();
+        let start = index[&start_context];
+
+        let mut rows = Vec::with_capacity(end + 1);
+        for context in &contexts {
+            let mut row: BTreeMap<usize, f64> = BTreeMap::new();
+            if let Some(followers) = chain.transitions().get(context) {
+                let total: usize = followers.values().sum();
+                for (token, &count) in followers {
+                    let j = match *token {
+                        Token::Stop => end,
+                        _ => {
+                            let mut next = context.clone();
+                            next.push(token.clone());
+                            let next = next[next.len() - chain.order()..].to_vec();
+                            match index.get(&next) {
+                                Some(&j) => j,
+                                None => continue,
+                            }
+                        }
+                    };
+                    *row.entry(j).or_insert(0.0) += count as f64 / total as f64;
+                }
+            }
+            rows.push(row.into_iter().collect());
+        }
+        rows.push(vec![(start, 1.0)]);
+
+        let mut states: Vec<State<T>> = contexts.into_iter().map(State::Context).collect();
+        states.push(State::End);
+        Analysis::from_rows(states, start, rows)
+    }
+}
+
+impl<T> Analysis<T> {
+    /// `states` must have exactly one `State::End`, and `matrix[i][j]` is the
+    /// probability of going from `states[i]` to `states[j]`.
+    pub fn from_matrix(states: Vec<State<T>>, start: usize, matrix: Vec<Vec<f64>>) -> Self {
+        assert!(matrix.iter().all(|row| row.len() == states.len()));
+        let rows = matrix
+            .into_iter()
+            .map(|row| {
+                row.into_iter()
+                    .enumerate()
+                    .filter(|&(_, p)| p > 0.0)
+                    .collect()
+            })
+            .collect();
+        Analysis::from_rows(states, start, rows)
+    }
+    /// Like `from_matrix`, with only the `(j, p)` pairs where `p > 0` in each
+    /// row.
+    pub fn from_rows(states: Vec<State<T>>, start: usize, rows: Vec<Vec<(usize, f64)>>) -> Self {
+        let end = states
+            .iter()
+            .position(|s| match *s {
+                State::End => true,
+                _ => false,
+            })
+            .expect("Analysis::from_rows, there must be an end state");
+        assert_eq!(states.len(), rows.len());
+        assert!(rows.iter().flat_map(|row| row).all(|&(j, _)| j < states.len()));
+        Analysis {
+            states,
+            start,
+            end,
+            rows,
+        }
+    }
+    pub fn states(&self) -> &[State<T>] {
+        &self.states
+    }
+    pub fn start(&self) -> usize {
+        self.start
+    }
+    pub fn end(&self) -> usize {
+        self.end
+    }
+    /// The states `i` goes to, with the probability of each.
+    pub fn row(&self, i: usize) -> &[(usize, f64)] {
+        &self.rows[i]
+    }
+    pub fn probability(&self, from: usize, to: usize) -> f64 {
+        self.rows[from]
+            .iter()
+            .find(|&&(j, _)| j == to)
+            .map_or(0.0, |&(_, p)| p)
+    }
+    pub fn index_of(&self, state: &State<T>) -> Option<usize>
+    where
+        T: PartialEq,
+    {
+        self.states.iter().position(|s| s == state)
+    }
+    /// The transition matrix as CSV, one `from,to,probability` line for every
+    /// transition that can happen.
+    pub fn to_csv(&self) -> String
+    where
+        T: Debug,
+    {
+        let name = |state: &State<T>| match *state {
+            State::Context(ref tokens) => format!("\"{:?}\"", tokens).replace('\n', " "),
+            State::End => "End".to_owned(),
+        };
+        let mut csv = String::from("from,to,probability\n");
+        for (state, row) in self.states.iter().zip(&self.rows) {
+            for &(j, p) in row {
+                csv += &format!("{},{},{}\n", name(state), name(&self.states[j]), p);
+            }
+        }
+        csv
+    }
+    /// The long run fraction of steps spent in each state, starting from the
+    /// start state.
+    ///
+    /// Iterates on the lazy chain `(I + P) / 2`, which has the same stationary
+    /// distribution but converges even when every sequence has the same length.
+    pub fn stationary_distribution(&self) -> Vec<f64> {
+        let n = self.states.len();
+        let mut pi = vec![0.0; n];
+        pi[self.start] = 1.0;
+        for _ in 0..100_000 {
+            let mut next: Vec<f64> = pi.iter().map(|p| p * 0.5).collect();
+            for (i, row) in self.rows.iter().enumerate() {
+                for &(j, p) in row {
+                    next[j] += pi[i] * p * 0.5;
+                }
+            }
+            let change: f64 = next.iter().zip(&pi).map(|(a, b)| (a - b).abs()).sum();
+            pi = next;
+            if change < 1e-13 {
+                break;
+            }
+        }
+        pi
+    }
+    /// Bits of information per generated step, in the long run.
+    pub fn entropy_rate(&self) -> f64 {
+        let pi = self.stationary_distribution();
+        -self.rows
+            .iter()
+            .zip(pi)
+            .map(|(row, p)| p * row.iter().map(|&(_, q)| q * q.log2()).sum::<f64>())
+            .sum::<f64>()
+    }
+    /// States, other than the end, that only ever lead back to themselves.
+    pub fn absorbing_states(&self) -> Vec<usize> {
+        (0..self.states.len())
+            .filter(|&i| i != self.end && self.probability(i, i) >= 1.0 - 1e-12)
+            .collect()
+    }
+    /// States that were never followed by anything. Generation stops there
+    /// without reaching the end of a sequence.
+    pub fn dead_ends(&self) -> Vec<usize> {
+        (0..self.states.len())
+            .filter(|&i| self.rows[i].iter().map(|&(_, p)| p).sum::<f64>() < 1e-12)
+            .collect()
+    }
+    /// States, other than the end, from which generation may loop forever:
+    /// they can get to states that lead neither to the end nor to a dead end.
+    pub fn trapped_states(&self) -> Vec<usize> {
+        let mut stops = self.dead_ends();
+        stops.push(self.end);
+        let may_stop = self.reaching(&stops);
+        let never_stop: Vec<usize> = (0..self.states.len()).filter(|&i| !may_stop[i]).collect();
+        let trapped = self.reaching(&never_stop);
+        (0..self.states.len())
+            .filter(|&i| i != self.end && trapped[i])
+            .collect()
+    }
+    /// The expected number of steps to get to `target` from each state.
+    /// Infinite for the states that may never get there.
+    pub fn hitting_times(&self, target: usize) -> Vec<f64> {
+        let n = self.states.len();
+        let reaches_target = self.reaching(&[target]);
+        let lost: Vec<usize> = (0..n).filter(|&i| !reaches_target[i]).collect();
+        let may_get_lost = self.reaching(&lost);
+        let unknowns: Vec<usize> = (0..n)
+            .filter(|&i| i != target && !may_get_lost[i])
+            .collect();
+
+        // h(i) = 1 + sum_j P(i, j) h(j) for every unknown i, with h(target) = 0.
+        // Every unknown gets to the target for sure, so Gauss-Seidel sweeps
+        // converge, about as fast as the chance of not being there yet fades.
+        let mut times = vec![f64::INFINITY; n];
+        times[target] = 0.0;
+        for &i in &unknowns {
+            times[i] = 0.0;
+        }
+        for _ in 0..100_000 {
+            let mut change: f64 = 0.0;
+            for &i in &unknowns {
+                let h = 1.0 + self.rows[i]
+                    .iter()
+                    .map(|&(j, p)| p * times[j])
+                    .sum::<f64>();
+                change = change.max((h - times[i]).abs() / h);
+                times[i] = h;
+            }
+            if change < 1e-14 {
+                break;
+            }
+        }
+        times
+    }
+    /// The expected number of tokens in a generated sequence.
+    pub fn expected_length(&self) -> f64 {
+        self.hitting_times(self.end)[self.start] - 1.0
+    }
+    /// Which states can get to any of the `targets`.
+    fn reaching(&self, targets: &[usize]) -> Vec<bool> {
+        let n = self.states.len();
+        let mut predecessors = vec![Vec::new(); n];
+        for (i, row) in self.rows.iter().enumerate() {
+            for &(j, _) in row {
+                predecessors[j].push(i);
+            }
+        }
+        let mut reaches = vec![false; n];
+        let mut queue: VecDeque<usize> = targets.iter().cloned().collect();
+        for &t in targets {
+            reaches[t] = true;
+        }
+        while let Some(j) = queue.pop_front() {
+            for &i in &predecessors[j] {
+                if !reaches[i] {
+                    reaches[i] = true;
+                    queue.push_back(i);
+                }
+            }
+        }
+        reaches
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::markov::{Chain, Token};
+    use drafts::markov_analysis::{Analysis, State};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn analyse_demo_chain() {
+        use self::Token::*;
+        let mut chain = Chain::of_order(2);
+        chain.feed(vec![1u8, 2, 3, 5]).feed(vec![3u8, 9, 2]);
+        let analysis = Analysis::of(&chain);
+
+        assert_eq!(analysis.states().len(), 9);
+        let start = analysis.start();
+        assert_eq!(analysis.states()[start], State::Context(vec![Start, Start]));
+        let after_1 = analysis.index_of(&State::Context(vec![Start, Word(1)])).unwrap();
+        let after_3 = analysis.index_of(&State::Context(vec![Start, Word(3)])).unwrap();
+        assert_eq!(analysis.probability(start, after_1), 0.5);
+        assert_eq!(analysis.probability(start, after_3), 0.5);
+        assert_eq!(analysis.probability(after_1, after_3), 0.0);
+        for i in 0..analysis.states().len() {
+            assert!(close(analysis.row(i).iter().map(|&(_, p)| p).sum(), 1.0));
+        }
+
+        assert!(analysis.absorbing_states().is_empty());
+        assert!(analysis.dead_ends().is_empty());
+        assert!(analysis.trapped_states().is_empty());
+        assert!(close(analysis.expected_length(), 3.5));
+
+        // Sequences of 4 and 3 tokens plus the end take 6 and 5 steps on
+        // average 5.5, with one coin flip each.
+        let pi = analysis.stationary_distribution();
+        assert!(close(pi.iter().sum(), 1.0));
+        assert!(close(pi[start], 1.0 / 5.5));
+        assert!(close(pi[analysis.end()], 1.0 / 5.5));
+        assert!(close(analysis.entropy_rate(), 1.0 / 5.5));
+
+        // One line per transition: two out of the start, one out of the
+        // other seven contexts and the end
+        let csv = analysis.to_csv();
+        assert_eq!(csv.lines().count(), 1 + 10);
+        assert!(csv.starts_with("from,to,probability\n\"[Start, Start]\",\"[Start, Word(1)]\",0.5\n"));
+    }
+
+    #[test]
+    fn hitting_times() {
+        let states = vec![
+            State::Context(vec![Token::Word("a")]),
+            State::Context(vec![Token::Word("b")]),
+            State::End,
+        ];
+        let analysis = Analysis::from_matrix(
+            states,
+            0,
+            vec![
+                vec![0.0, 1.0, 0.0],
+                vec![0.5, 0.0, 0.5],
+                vec![1.0, 0.0, 0.0],
+            ],
+        );
+        let times = analysis.hitting_times(2);
+        assert!(close(times[0], 4.0));
+        assert!(close(times[1], 3.0));
+        assert_eq!(times[2], 0.0);
+        assert!(close(analysis.expected_length(), 3.0));
+    }
+
+    #[test]
+    fn loops_and_dead_ends() {
+        let word = |w| State::Context(vec![Token::Word(w)]);
+        let states = vec![word("a"), word("b"), word("c"), word("d"), State::End];
+        let analysis = Analysis::from_matrix(
+            states,
+            0,
+            vec![
+                vec![0.0, 0.5, 0.0, 0.0, 0.5],
+                vec![0.0, 0.0, 1.0, 0.0, 0.0],
+                vec![0.0, 0.0, 1.0, 0.0, 0.0],
+                vec![0.0, 0.0, 0.0, 0.0, 0.0],
+                vec![1.0, 0.0, 0.0, 0.0, 0.0],
+            ],
+        );
+        assert_eq!(analysis.absorbing_states(), vec![2]);
+        assert_eq!(analysis.dead_ends(), vec![3]);
+        // `a` may go on to `b` and loop in `c`, `d` stops generation
+        assert_eq!(analysis.trapped_states(), vec![0, 1, 2]);
+        assert!(analysis.expected_length().is_infinite());
+        // Once stuck in `c` all the time is spent there
+        assert!(analysis.stationary_distribution()[2] > 0.99);
+    }
+}
@@ -0,0 +1,348 @@
+use drafts::markov::{Chain, Sampler, Token};
+use rand::Rng;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error::Error;
+use std::fmt;
+
+/// Rules a generated sequence must follow. Tokens breaking them are never
+/// sampled, and a sequence that gets stuck is thrown away and generated again,
+/// up to `retries` times.
+///
+/// ```ignore
+/// let lines = Constraints::new()
+///     .length(3, 12)
+///     .ending_with(".".to_owned())
+///     .avoid_copying(&corpus, 4)
+///     .generate(&chain, &Sampler::default(), &mut rng)?;
+/// ```
+#[derive(Clone, Debug)]
+pub struct Constraints<T>
+where
+    T: Ord + Clone,
+{
+    min_length: usize,
+    max_length: usize,
+    start: Option<T>,
+    end: Option<T>,
+    banned: BTreeSet<Vec<T>>,
+    copied: Option<(usize, BTreeSet<Vec<T>>)>,
+    retries: usize,
+}
+
+impl<T> Default for Constraints<T>
+where
+    T: Ord + Clone,
+{
+    fn default() -> Self {
+        Constraints {
+            min_length: 1,
+            max_length: 1000,
+            start: None,
+            end: None,
+            banned: BTreeSet::new(),
+            copied: None,
+            retries: 100,
+        }
+    }
+}
+
+impl<T> Constraints<T>
+where
+    T: Ord + Clone,
+{
+    pub fn new() -> Self {
+        Constraints::default()
+    }
+    /// Number of tokens, both ends included. Sequences are never empty, so a
+    /// `min_length` of 0 is taken as 1.
+    pub fn length(self, min_length: usize, max_length: usize) -> Self {
+        Constraints {
+            min_length: min_length.max(1),
+            max_length,
+            ..self
+        }
+    }
+    pub fn starting_with(self, token: T) -> Self {
+        Constraints {
+            start: Some(token),
+            ..self
+        }
+    }
+    pub fn ending_with(self, token: T) -> Self {
+        Constraints {
+            end: Some(token),
+            ..self
+        }
+    }
+    /// The sequence may not contain `n_gram` anywhere.
+    pub fn ban<S: AsRef<[T]>>(mut self, n_gram: S) -> Self {
+        if !n_gram.as_ref().is_empty() {
+            self.banned.insert(n_gram.as_ref().to_vec());
+        }
+        self
+    }
+    /// The sequence may not repeat more than `k` consecutive tokens of any of
+    /// the training sequences.
+    pub fn avoid_copying<S: AsRef<[T]>>(self, training: &[S], k: usize) -> Self {
+        let copied = training
+            .iter()
+            .flat_map(|sequence| sequence.as_ref().windows(k + 1).map(|w| w.to_vec()))
+            .collect();
+        Constraints {
+            copied: Some((k, copied)),
+            ..self
+        }
+    }
+    /// How many sequences are thrown away before giving up.
+    pub fn retries(self, retries: usize) -> Self {
+        Constraints { retries, ..self }
+    }
+    pub fn generate<R: Rng>(
+        &self,
+        chain: &Chain<T>,
+        sampler: &Sampler,
+        rng: &mut R,
+    ) -> Result<Vec<T>, ConstraintError> {
+        if self.min_length > self.max_length {
+            return Err(ConstraintError::Contradictory(format!(
+                "the minimum length {} is over the maximum {}",
+                self.min_length, self.max_length
+            )));
+        }
+        let mut failures = Failures::default();
+        for _ in 0..=self.retries {
+            match self.attempt(chain, sampler, rng) {
+                Ok(sequence) => return Ok(sequence),
+                Err(failure) => failures.count(failure),
+            }
+        }
+        Err(ConstraintError::GaveUp {
+            attempts: self.retries + 1,
+            failures,
+        })
+    }
+    fn attempt<R: Rng>(
+        &self,
+        chain: &Chain<T>,
+        sampler: &Sampler,
+        rng: &mut R,
+    ) -> Result<Vec<T>, Failure> {
+        let mut context = vec![Token::Start; chain.order()];
+        let mut sequence = Vec::new();
+        loop {
+            let followers = chain.followers(&context).ok_or(Failure::DeadEnd)?;
+            let allowed: BTreeMap<Token<T>, usize> = followers
+                .iter()
+                .filter(|&(token, _)| self.allows(&sequence, token))
+                .map(|(token, &count)| (token.clone(), count))
+                .collect();
+            if allowed.is_empty() {
+                return Err(if sequence.len() == self.max_length {
+                    Failure::TooLong
+                } else if followers.keys().any(|t| *t == Token::Stop) {
+                    Failure::WrongEnd
+                } else {
+                    Failure::DeadEnd
+                });
+            }
+            match sampler.sample(&allowed, rng) {
+                Token::Word(token) => {
+                    sequence.push(token.clone());
+                    context.push(Token::Word(token));
+                }
+                Token::Start | Token::Stop => return Ok(sequence),
+            }
+        }
+    }
+    /// Whether `token` may follow `sequence`.
+    fn allows(&self, sequence: &[T], token: &Token<T>) -> bool {
+        match *token {
+            Token::Start => false,
+            Token::Stop => {
+                sequence.len() >= self.min_length
+                    && self.end.as_ref().map_or(true, |end| sequence.last() == Some(end))
+            }
+            Token::Word(ref word) => {
+                if sequence.len() >= self.max_length {
+                    return false;
+                }
+                if sequence.is_empty() && self.start.as_ref().map_or(false, |s| s != word) {
+                    return false;
+                }
+                let ends_with = |n_gram: &[T]| {
+                    let (last, rest) = n_gram.split_last().unwrap();
+                    last == word && rest.len() <= sequence.len()
+                        && &sequence[sequence.len() - rest.len()..] == rest
+                };
+                if self.banned.iter().any(|n_gram| ends_with(n_gram)) {
+                    return false;
+                }
+                match self.copied {
+                    Some((k, ref copied)) if sequence.len() >= k => {
+                        let mut window = sequence[sequence.len() - k..].to_vec();
+                        window.push(word.clone());
+                        !copied.contains(&window)
+                    }
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+/// Why a single attempt was thrown away.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Failure {
+    /// Every token that could follow broke a constraint.
+    DeadEnd,
+    /// The chain could only have stopped on a token other than the required end.
+    WrongEnd,
+    /// The maximum length was reached without being able to stop.
+    TooLong,
+}
+
+/// How many attempts failed for each reason.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Failures {
+    pub dead_ends: usize,
+    pub wrong_ends: usize,
+    pub too_long: usize,
+}
+
+impl Failures {
+    fn count(&mut self, failure: Failure) {
+        match failure {
+            Failure::DeadEnd => self.dead_ends += 1,
+            Failure::WrongEnd => self.wrong_ends += 1,
+            Failure::TooLong => self.too_long += 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConstraintError {
+    /// No sequence could ever satisfy the constraints.
+    Contradictory(String),
+    GaveUp { attempts: usize, failures: Failures },
+}
+
+impl fmt::Display for ConstraintError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConstraintError::Contradictory(ref reason) => {
+                write!(f, "contradictory constraints: {}", reason)
+            }
+            ConstraintError::GaveUp {
+                attempts,
+                ref failures,
+            } => write!(
+                f,
+                "no sequence met the constraints after {} attempts \
+                 ({} dead ends, {} wrong endings, {} too long)",
+                attempts, failures.dead_ends, failures.wrong_ends, failures.too_long
+            ),
+        }
+    }
+}
+
+impl Error for ConstraintError {}
+
+#[cfg(test)]
+mod test {
+    use drafts::markov::{seeded_rng, Chain, Sampler};
+    use drafts::markov_constraints::{ConstraintError, Constraints};
+
+    fn corpus() -> Vec<Vec<u8>> {
+        vec![
+            vec![1, 2, 3, 4, 5],
+            vec![1, 2, 6, 4, 7],
+            vec![8, 2, 3, 4, 7],
+            vec![8, 9],
+        ]
+    }
+
+    fn chain() -> Chain<u8> {
+        let mut chain = Chain::of_order(1);
+        for sequence in corpus() {
+            chain.feed(sequence);
+        }
+        chain
+    }
+
+    fn generate(constraints: &Constraints<u8>) -> Vec<Vec<u8>> {
+        let mut rng = seeded_rng(3);
+        (0..50)
+            .map(|_| {
+                constraints
+                    .generate(&chain(), &Sampler::default(), &mut rng)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lengths_and_ends() {
+        // Only [8, 9] is short enough
+        for sequence in generate(&Constraints::new().length(1, 4)) {
+            assert_eq!(sequence, vec![8, 9]);
+        }
+        for sequence in generate(&Constraints::new().length(3, 5)) {
+            assert_eq!(sequence.len(), 5, "{:?}", sequence);
+        }
+        // A minimum of 0 is a minimum of 1, the start and end still apply
+        for sequence in generate(&Constraints::new().length(0, 3).starting_with(8)) {
+            assert_eq!(sequence, vec![8, 9]);
+        }
+        for sequence in generate(&Constraints::new().starting_with(8).ending_with(7)) {
+            assert_eq!(sequence.first(), Some(&8));
+            assert_eq!(sequence.last(), Some(&7));
+        }
+    }
+
+    #[test]
+    fn banned_n_grams() {
+        let constraints = Constraints::new().ban([3, 4]).ban([9]);
+        for sequence in generate(&constraints) {
+            assert!(!sequence.windows(2).any(|w| w == [3, 4]), "{:?}", sequence);
+            assert!(!sequence.contains(&9));
+        }
+    }
+
+    #[test]
+    fn no_copying() {
+        let corpus = corpus();
+        let constraints = Constraints::new().avoid_copying(&corpus, 2);
+        for sequence in generate(&constraints) {
+            for copied in corpus.iter().flat_map(|s| s.windows(3)) {
+                assert!(!sequence.windows(3).any(|w| w == copied), "{:?}", sequence);
+            }
+        }
+    }
+
+    #[test]
+    fn impossible_constraints() {
+        let mut rng = seeded_rng(3);
+        let sampler = Sampler::default();
+        match Constraints::new().length(5, 2).generate(&chain(), &sampler, &mut rng) {
+            Err(ConstraintError::Contradictory(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Sequences are never empty, so nothing fits in a length of 0
+        match Constraints::new().length(0, 0).generate(&chain(), &sampler, &mut rng) {
+            Err(ConstraintError::Contradictory(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Every sequence ends in 5, 7 or 9
+        match Constraints::new()
+            .ending_with(4)
+            .retries(9)
+            .generate(&chain(), &sampler, &mut rng)
+        {
+            Err(ConstraintError::GaveUp { attempts, failures }) => {
+                assert_eq!(attempts, 10);
+                assert_eq!(failures.dead_ends + failures.wrong_ends + failures.too_long, 10);
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use drafts::markov::{seeded_rng, Chain, Sampler};
+use mursten::{Data, Updater};
+use mursten_blocks::midi::{MidiMessage, OnMidiMessage};
+use mursten_blocks::time::Clock;
+use rand::StdRng;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A note starting or ending at `time` seconds on the clock the sequencer follows.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoteEvent {
+    pub time: f32,
+    pub key: u8,
+    pub velocity: u8,
+}
+
+impl NoteEvent {
+    pub fn is_on(&self) -> bool {
+        self.velocity > 0
+    }
+    pub fn to_midi_message(&self) -> MidiMessage {
+        if self.is_on() {
+            MidiMessage::NoteOn(self.key, self.velocity)
+        } else {
+            MidiMessage::NoteOff(self.key, 0)
+        }
+    }
+}
+
+/// Plays an endless melody made of phrases generated by a Markov chain over
+/// note numbers, one note per step at a fixed tempo.
+pub struct Sequencer {
+    chain: Chain<u8>,
+    sampler: Sampler,
+    rng: StdRng,
+    step: f32,
+    gate: f32,
+    velocity: u8,
+    phrase: VecDeque<u8>,
+    next_note: f32,
+    pending: Vec<NoteEvent>,
+}
+
+impl Sequencer {
+    pub fn new(chain: Chain<u8>, seed: u64) -> Self {
+        Sequencer {
+            chain,
+            sampler: Sampler::default().max_length(64),
+            rng: seeded_rng(seed),
+            step: 0.5,
+            gate: 0.8,
+            velocity: 100,
+            phrase: VecDeque::new(),
+            next_note: 0.0,
+            pending: Vec::new(),
+        }
+    }
+    /// Trains an order `order` chain on the given phrases.
+    pub fn trained_on(phrases: &[Vec<u8>], order: usize, seed: u64) -> Self {
+        let mut chain = Chain::of_order(order);
+        for phrase in phrases {
+            chain.feed(phrase);
+        }
+        Sequencer::new(chain, seed)
+    }
+    /// One note every `beats` beats at `bpm` beats per minute.
+    pub fn tempo(self, bpm: f32, beats: f32) -> Self {
+        Sequencer {
+            step: 60.0 / bpm * beats,
+            ..self
+        }
+    }
+    /// Fraction of the step a note is held for.
+    pub fn gate(self, gate: f32) -> Self {
+        Sequencer {
+            gate: gate.max(0.0).min(1.0),
+            ..self
+        }
+    }
+    pub fn velocity(self, velocity: u8) -> Self {
+        Sequencer { velocity, ..self }
+    }
+    pub fn sampler(self, sampler: Sampler) -> Self {
+        Sequencer { sampler, ..self }
+    }
+    /// The events due up to `time`, in order, that weren't returned before.
+    pub fn advance(&mut self, time: f32) -> Vec<NoteEvent> {
+        while self.next_note <= time {
+            let key = match self.next_key() {
+                Some(key) => key,
+                None => break,
+            };
+            self.pending.push(NoteEvent {
+                time: self.next_note,
+                key,
+                velocity: self.velocity,
+            });
+            self.pending.push(NoteEvent {
+                time: self.next_note + self.step * self.gate,
+                key,
+                velocity: 0,
+            });
+            self.next_note += self.step;
+        }
+        self.pending
+            .sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+        let due = self.pending.iter().take_while(|e| e.time <= time).count();
+        self.pending.drain(..due).collect()
+    }
+    fn next_key(&mut self) -> Option<u8> {
+        if self.phrase.is_empty() {
+            self.phrase = self.chain
+                .generate_with(&self.sampler, &mut self.rng)
+                .into_iter()
+                .collect();
+        }
+        self.phrase.pop_front()
+    }
+}
+
+/// Data with a clock for the melody to follow, usually the scene's own one
+/// stepped by `ClockUpdater`, so pausing the scene pauses the melody too.
+pub trait GetClock {
+    fn clock(&self) -> &Clock;
+}
+
+/// Feeds the sequencer's notes to the data as if they came from a MIDI device,
+/// at the time the data's clock shows.
+pub struct MelodyUpdater {
+    sequencer: Sequencer,
+}
+
+impl MelodyUpdater {
+    pub fn new(sequencer: Sequencer) -> Self {
+        MelodyUpdater { sequencer }
+    }
+}
+
+impl<B, D> Updater<B, D> for MelodyUpdater
+where
+    D: Data + GetClock + OnMidiMessage,
+{
+    fn update(&mut self, _: &mut B, data: &mut D) {
+        let time = data.clock().time_in_sec();
+        for event in self.sequencer.advance(time) {
+            data.on_midi_message(event.to_midi_message());
+        }
+    }
+}
+
+/// The keys of every note on event in a standard MIDI file, track after track.
+pub fn notes_from_midi<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, MidiFileError> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    parse_notes(&bytes)
+}
+
+pub fn parse_notes(bytes: &[u8]) -> Result<Vec<u8>, MidiFileError> {
+    let mut reader = Bytes { bytes, position: 0 };
+    if reader.take(4)? != b"MThd" {
+        return Err(MidiFileError::NotMidi);
+    }
+    let header_len = reader.u32()? as usize;
+    reader.take(header_len)?;
+
+    let mut notes = Vec::new();
+    while reader.position < bytes.len() {
+        let id = reader.take(4)?;
+        let len = reader.u32()? as usize;
+        let chunk = reader.take(len)?;
+        if id == b"MTrk" {
+            track_notes(chunk, &mut notes)?;
+        }
+    }
+    Ok(notes)
+}
+
+fn track_notes(track: &[u8], notes: &mut Vec<u8>) -> Result<(), MidiFileError> {
+    let mut reader = Bytes {
+        bytes: track,
+        position: 0,
+    };
+    let mut running_status = None;
+    while reader.position < track.len() {
+        reader.var_len()?;
+        let status = match reader.peek()? {
+            byte if byte >= 0x80 => {
+                reader.position += 1;
+                byte
+            }
+            _ => running_status.ok_or(MidiFileError::Malformed)?,
+        };
+        match status {
+            0xFF => {
+                reader.take(1)?;
+                let len = reader.var_len()?;
+                reader.take(len)?;
+            }
+            0xF0 | 0xF7 => {
+                let len = reader.var_len()?;
+                reader.take(len)?;
+            }
+            _ => {
+                running_status = Some(status);
+                match status & 0xF0 {
+                    0xC0 | 0xD0 => {
+                        reader.take(1)?;
+                    }
+                    0x90 => {
+                        let data = reader.take(2)?;
+                        if data[1] > 0 {
+                            notes.push(data[0]);
+                        }
+                    }
+                    _ => {
+                        reader.take(2)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct Bytes<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Bytes<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], MidiFileError> {
+        if self.position + len > self.bytes.len() {
+            return Err(MidiFileError::Truncated);
+        }
+        let taken = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(taken)
+    }
+    fn peek(&self) -> Result<u8, MidiFileError> {
+        self.bytes
+            .get(self.position)
+            .cloned()
+            .ok_or(MidiFileError::Truncated)
+    }
+    fn u32(&mut self) -> Result<u32, MidiFileError> {
+        Ok(self.take(4)?
+            .iter()
+            .fold(0, |n, &byte| (n << 8) | byte as u32))
+    }
+    fn var_len(&mut self) -> Result<usize, MidiFileError> {
+        let mut n = 0;
+        for _ in 0..4 {
+            let byte = self.take(1)?[0];
+            n = (n << 7) | (byte & 0x7F) as usize;
+            if byte < 0x80 {
+                return Ok(n);
+            }
+        }
+        Err(MidiFileError::Malformed)
+    }
+}
+
+#[derive(Debug)]
+pub enum MidiFileError {
+    Io(io::Error),
+    NotMidi,
+    Truncated,
+    Malformed,
+}
+
+impl fmt::Display for MidiFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MidiFileError::Io(ref e) => write!(f, "{}", e),
+            MidiFileError::NotMidi => write!(f, "not a standard MIDI file"),
+            MidiFileError::Truncated => write!(f, "the MIDI file ends abruptly"),
+            MidiFileError::Malformed => write!(f, "malformed MIDI event"),
+        }
+    }
+}
+
+impl Error for MidiFileError {}
+
+impl From<io::Error> for MidiFileError {
+    fn from(e: io::Error) -> Self {
+        MidiFileError::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::melody::{parse_notes, GetClock, MelodyUpdater, MidiFileError, NoteEvent, Sequencer};
+    use mursten::{Data, Updater};
+    use mursten_blocks::midi::{MidiMessage, OnMidiMessage};
+    use mursten_blocks::time::{Clock, Tick};
+    use std::time::Duration;
+
+    fn scale() -> Sequencer {
+        Sequencer::trained_on(&[vec![60, 62, 64, 65, 67]], 1, 7).tempo(120.0, 1.0)
+    }
+
+    #[test]
+    fn note_timings() {
+        let events = scale().advance(2.0);
+        let expected = vec![
+            (0.0, true),
+            (0.4, false),
+            (0.5, true),
+            (0.9, false),
+            (1.0, true),
+            (1.4, false),
+            (1.5, true),
+            (1.9, false),
+            (2.0, true),
+        ];
+        assert_eq!(events.len(), expected.len());
+        for (event, &(time, on)) in events.iter().zip(expected.iter()) {
+            assert!((event.time - time).abs() < 1e-5, "{:?}", event);
+            assert_eq!(event.is_on(), on);
+        }
+        // Every note is released with the key it was pressed with
+        for pair in events.chunks(2).filter(|pair| pair.len() == 2) {
+            assert_eq!(pair[0].key, pair[1].key);
+        }
+        assert!(events.iter().all(|e| [60, 62, 64, 65, 67].contains(&e.key)));
+    }
+
+    #[test]
+    fn advancing_in_steps_matches_one_go() {
+        let all = scale().advance(9.75);
+        let mut sequencer = scale();
+        let mut stepped: Vec<NoteEvent> = Vec::new();
+        for frame in 1..488 {
+            stepped.extend(sequencer.advance(frame as f32 * 0.02));
+        }
+        stepped.extend(sequencer.advance(9.75));
+        assert_eq!(stepped, all);
+    }
+
+    struct Keys {
+        clock: Clock,
+        played: Vec<(u8, bool)>,
+    }
+
+    impl Data for Keys {}
+
+    impl GetClock for Keys {
+        fn clock(&self) -> &Clock {
+            &self.clock
+        }
+    }
+
+    impl OnMidiMessage for Keys {
+        fn on_midi_message(&mut self, msg: MidiMessage) {
+            match msg {
+                MidiMessage::NoteOn(key, _) => self.played.push((key, true)),
+                MidiMessage::NoteOff(key, _) => self.played.push((key, false)),
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn updater_follows_the_clock() {
+        let mut updater = MelodyUpdater::new(scale());
+        let mut keys = Keys {
+            clock: Clock::new(),
+            played: Vec::new(),
+        };
+        // 0.98s in: two notes pressed at 0.0 and 0.5, released at 0.4 and 0.9
+        for _ in 0..49 {
+            keys.clock += Tick::new(Duration::from_millis(20));
+            updater.update(&mut (), &mut keys);
+        }
+        let on_off: Vec<bool> = keys.played.iter().map(|&(_, on)| on).collect();
+        assert_eq!(on_off, vec![true, false, true, false]);
+        let expected: Vec<(u8, bool)> = scale()
+            .advance(0.98)
+            .iter()
+            .map(|e| (e.key, e.is_on()))
+            .collect();
+        assert_eq!(keys.played, expected);
+
+        // A paused clock holds the melody where it is
+        for _ in 0..10 {
+            updater.update(&mut (), &mut keys);
+        }
+        assert_eq!(keys.played.len(), 4);
+    }
+
+    #[test]
+    fn parse_midi_file() {
+        let mut file = b"MThd\x00\x00\x00\x06\x00\x00\x00\x01\x00\x60".to_vec();
+        let track: &[u8] = &[
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, // tempo
+            0x00, 0x90, 60, 100, // note on
+            0x60, 60, 0, // running status note on with velocity 0
+            0x00, 64, 90, // running status note on
+            0x81, 0x40, 0x80, 64, 0, // note off after a two byte delta
+            0x00, 0xC0, 5, // program change
+            0x00, 0x90, 67, 80,
+            0x00, 0xFF, 0x2F, 0x00, // end of track
+        ];
+        file.extend(b"MTrk");
+        file.extend(&[0, 0, 0, track.len() as u8]);
+        file.extend(track);
+        assert_eq!(parse_notes(&file).unwrap(), vec![60, 64, 67]);
+
+        match parse_notes(&file[..file.len() - 3]) {
+            Err(MidiFileError::Truncated) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match parse_notes(b"RIFF") {
+            Err(MidiFileError::NotMidi) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
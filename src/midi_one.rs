@@ -1,28 +1,111 @@
+extern crate bincode;
+extern crate image;
 extern crate midir;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
 extern crate nalgebra;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
-use mursten::{Application, Data, Renderer};
+/// The drafts this scene shares with the library, built from the same files.
+#[path = "."]
+#[allow(dead_code)]
+mod drafts {
+    #[cfg(test)]
+    pub mod golden;
+    pub mod markov;
+    pub mod markov_analysis;
+    pub mod melody;
+    pub mod software_backend;
+}
+
+use mursten::{Application, Backend, Data, Renderer};
 use mursten_blocks::camera::{Camera, CameraUpdater, GetCamera};
 use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
 use mursten_blocks::midi::{MidiMessage, MidiUpdater, OnMidiMessage};
 use mursten_blocks::time::{Clock, ClockUpdater, OnTick, Tick};
 use mursten_vulkan_backend::VulkanBackend;
 
+use drafts::melody::{notes_from_midi, GetClock, MelodyUpdater, Sequencer};
+use drafts::software_backend::{software_backend_from_args, MeshBackend, SoftwareCameraUpdater};
+
 use nalgebra::*;
+use std::env;
+use std::process;
 
 pub fn main() {
-    let backend = VulkanBackend::new();
+    let melody = match melody_from_args() {
+        Ok(melody) => melody,
+        Err(e) => {
+            eprintln!("midi_one: {}", e);
+            process::exit(2);
+        }
+    };
     let scene = Scene::default();
-    Application::new(backend)
-        .add_updater(CameraUpdater::new())
-        .add_updater(ClockUpdater::new())
-        .add_updater(MidiUpdater::prompt())
-        .add_renderer(Visual::new())
-        .run(scene);
+    match software_backend_from_args() {
+        // Nobody is there to pick a MIDI device
+        Some(backend) => run(
+            Application::new(backend).add_updater(SoftwareCameraUpdater),
+            scene,
+            melody,
+            false,
+        ),
+        None => run(
+            Application::new(VulkanBackend::new()).add_updater(CameraUpdater::new()),
+            scene,
+            melody,
+            true,
+        ),
+    }
+}
+
+/// Plays `melody`, or else what a MIDI device plays when `midi` is set.
+fn run<B>(app: Application<B, Scene>, scene: Scene, melody: Option<Sequencer>, midi: bool)
+where
+    B: Backend<Scene> + MeshBackend + 'static,
+{
+    let app = app.add_updater(ClockUpdater::new());
+    let app = match melody {
+        Some(sequencer) => app.add_updater(MelodyUpdater::new(sequencer)),
+        None if midi => app.add_updater(MidiUpdater::prompt()),
+        None => app,
+    };
+    app.add_renderer(Visual::new()).run(scene);
+}
+
+/// `--melody song.mid` or `--notes 36,38,40` plays a melody generated from
+/// those notes instead of listening to a MIDI device, which `--software`
+/// never does. Only keys 24 to 59 show up in the spiral.
+fn melody_from_args() -> Result<Option<Sequencer>, String> {
+    let args: Vec<String> = env::args().collect();
+    let value = |flag: &str| match args.iter().position(|a| a == flag) {
+        None => Ok(None),
+        Some(i) => match args.get(i + 1) {
+            Some(value) if !value.starts_with("--") => Ok(Some(value.clone())),
+            _ => Err(format!("{} needs a value", flag)),
+        },
+    };
+    let notes = if let Some(path) = value("--melody")? {
+        notes_from_midi(&path).map_err(|e| format!("can't read the melody in {}: {}", path, e))?
+    } else if let Some(list) = value("--notes")? {
+        list.split(',')
+            .map(|n| {
+                n.trim()
+                    .parse()
+                    .map_err(|_| format!("{:?} is not a MIDI note number", n.trim()))
+            })
+            .collect::<Result<Vec<u8>, String>>()?
+    } else {
+        return Ok(None);
+    };
+    if notes.is_empty() {
+        return Err("the melody has no notes".to_string());
+    }
+    Ok(Some(Sequencer::trained_on(&[notes], 2, 0).tempo(110.0, 0.5)))
 }
 
 struct Scene {
@@ -51,6 +134,12 @@ impl Default for Scene {
 
 impl Data for Scene {}
 
+impl GetClock for Scene {
+    fn clock(&self) -> &Clock {
+        &self.clock
+    }
+}
+
 impl OnTick for Scene {
     fn on_tick(&mut self, tick: Tick) {
         if !self.paused {
@@ -125,8 +214,11 @@ fn spiral_points(keyboard: Vec<u8>) -> Vec<Vertex> {
         .collect()
 }
 
-impl Renderer<VulkanBackend, Scene> for Visual {
-    fn render(&mut self, backend: &mut VulkanBackend, scene: &Scene) {
+impl<B> Renderer<B, Scene> for Visual
+where
+    B: MeshBackend,
+{
+    fn render(&mut self, backend: &mut B, scene: &Scene) {
         let keyboard = interpolate(
             scene.keyboard.iter().skip(24).take(36).cloned().collect(),
             self.last_keyboard.clone(),
@@ -180,3 +272,30 @@ impl Renderer<VulkanBackend, Scene> for Visual {
         backend.queue_render(mesh);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Scene, Visual};
+    use drafts::golden::{FixedClockUpdater, Golden, Harness, Script};
+    use drafts::software_backend::SoftwareCameraUpdater;
+    use mursten_blocks::midi::{MidiMessage, OnMidiMessage};
+
+    #[test]
+    fn golden_frame() {
+        let press = |key, vel| move |scene: &mut Scene| scene.on_midi_message(MidiMessage::NoteOn(key, vel));
+        // A chord, and its root released before the frame
+        let keyboard = Script::new()
+            .at(0, press(48, 100))
+            .at(4, press(52, 80))
+            .at(8, press(55, 127))
+            .at(16, |scene: &mut Scene| scene.on_midi_message(MidiMessage::NoteOff(48, 0)));
+        let mut scene = Scene::default();
+        let image = Harness::new(160, 160)
+            .add_updater(FixedClockUpdater::default())
+            .add_updater(SoftwareCameraUpdater)
+            .add_updater(keyboard)
+            .add_renderer(Visual::new())
+            .render(&mut scene, 20);
+        Golden::new("midi_one").assert_matches(&image);
+    }
+}
@@ -1,6 +1,21 @@
 pub mod prob;
 pub mod prob2;
 pub mod recursive_template;
+pub mod pipeline_config;
 pub mod visuals;
 pub mod markov;
+pub mod markov_analysis;
+pub mod markov_constraints;
+pub mod melody;
+pub mod layout;
 pub mod weather;
+pub mod weather_location;
+pub mod weather_units;
+pub mod weather_cache;
+pub mod weather_providers;
+pub mod weather_fetcher;
+pub mod weather_format;
+pub mod weather_history;
+pub mod weather_scene;
+pub mod software_backend;
+pub mod golden;
@@ -0,0 +1,400 @@
+use drafts::recursive_template::{Chain, Data, InterceptableChain, Interceptor, Processor};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use toml;
+
+/// A chain of boxed processors, built at runtime from a config.
+pub struct Pipeline<D>
+where
+    D: Data,
+{
+    processors: Vec<Box<Processor<D>>>,
+}
+
+impl<D> Pipeline<D>
+where
+    D: Data,
+{
+    pub fn new() -> Self {
+        Pipeline {
+            processors: Vec::new(),
+        }
+    }
+    pub fn len(&self) -> usize {
+        self.processors.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+}
+
+impl<D> Default for Pipeline<D>
+where
+    D: Data,
+{
+    fn default() -> Self {
+        Pipeline::new()
+    }
+}
+
+impl<D> Chain<D> for Pipeline<D>
+where
+    D: Data,
+{
+    fn process(&mut self, data: D) -> D {
+        self.processors
+            .iter_mut()
+            .fold(data, |data, processor| processor.process(data))
+    }
+}
+
+impl<D> InterceptableChain<D> for Pipeline<D>
+where
+    D: Data,
+{
+    fn process_intercepted(&mut self, data: D, interceptor: &mut Interceptor<D>) -> D {
+        self.processors.iter_mut().fold(data, |data, processor| {
+            interceptor.before(&data);
+            let data = interceptor.around(data, &mut |data| processor.process(data));
+            interceptor.after(&data);
+            data
+        })
+    }
+}
+
+/// The file layout: one `[[stage]]` table per processor, in order. Every key
+/// other than `kind` is a parameter for the stage's factory, and a key the
+/// factory doesn't ask for is an error.
+///
+/// ```toml
+/// [[stage]]
+/// kind = "physics"
+///
+/// [[stage]]
+/// kind = "render"
+/// screen = "1"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct PipelineConfig {
+    #[serde(default, rename = "stage")]
+    stages: Vec<StageConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+struct StageConfig {
+    kind: String,
+    #[serde(flatten)]
+    parameters: BTreeMap<String, toml::Value>,
+}
+
+impl FromStr for PipelineConfig {
+    type Err = ConfigError;
+    fn from_str(source: &str) -> Result<Self, ConfigError> {
+        toml::from_str(source).map_err(ConfigError::Syntax)
+    }
+}
+
+/// The parameters of one stage, as handed to its factory.
+pub struct Parameters<'a> {
+    stage: usize,
+    kind: &'a str,
+    values: &'a BTreeMap<String, toml::Value>,
+    asked: RefCell<BTreeSet<String>>,
+}
+
+impl<'a> Parameters<'a> {
+    pub fn get<T>(&self, name: &str) -> Result<T, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.raw(name) {
+            Some(value) => self.parse(name, value),
+            None => Err(ConfigError::MissingParameter {
+                stage: self.stage,
+                kind: self.kind.to_owned(),
+                name: name.to_owned(),
+            }),
+        }
+    }
+    pub fn get_or<T>(&self, name: &str, default: T) -> Result<T, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match self.raw(name) {
+            Some(value) => self.parse(name, value),
+            None => Ok(default),
+        }
+    }
+    /// A parameter in the config the factory never asked for, most likely a
+    /// misspelt one.
+    fn unknown(&self) -> Option<&str> {
+        let asked = self.asked.borrow();
+        self.values
+            .keys()
+            .find(|name| !asked.contains(name.as_str()))
+            .map(|name| name.as_str())
+    }
+    fn raw(&self, name: &str) -> Option<String> {
+        self.asked.borrow_mut().insert(name.to_owned());
+        self.values.get(name).map(|value| match *value {
+            toml::Value::String(ref s) => s.clone(),
+            ref other => other.to_string(),
+        })
+    }
+    fn parse<T>(&self, name: &str, value: String) -> Result<T, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        match value.parse() {
+            Ok(parsed) => Ok(parsed),
+            Err(e) => Err(ConfigError::BadParameter {
+                stage: self.stage,
+                kind: self.kind.to_owned(),
+                name: name.to_owned(),
+                reason: e.to_string(),
+                value,
+            }),
+        }
+    }
+}
+
+type Factory<D> = Box<Fn(&Parameters) -> Result<Box<Processor<D>>, ConfigError>>;
+
+/// Knows how to build a processor for every stage kind a config may name.
+pub struct Registry<D>
+where
+    D: Data,
+{
+    factories: HashMap<String, Factory<D>>,
+}
+
+impl<D> Registry<D>
+where
+    D: Data + 'static,
+{
+    pub fn new() -> Self {
+        Registry {
+            factories: HashMap::new(),
+        }
+    }
+    pub fn register<F, P>(mut self, kind: &str, factory: F) -> Self
+    where
+        F: Fn(&Parameters) -> Result<P, ConfigError> + 'static,
+        P: Processor<D> + 'static,
+    {
+        self.factories.insert(
+            kind.to_owned(),
+            Box::new(move |parameters: &Parameters| {
+                factory(parameters).map(|p| Box::new(p) as Box<Processor<D>>)
+            }),
+        );
+        self
+    }
+    pub fn load(&self, source: &str) -> Result<Pipeline<D>, ConfigError> {
+        self.build(&source.parse()?)
+    }
+    pub fn build(&self, config: &PipelineConfig) -> Result<Pipeline<D>, ConfigError> {
+        let mut pipeline = Pipeline::new();
+        for (stage, stage_config) in config.stages.iter().enumerate() {
+            let factory = self.factories.get(&stage_config.kind).ok_or_else(|| {
+                ConfigError::UnknownKind {
+                    stage,
+                    kind: stage_config.kind.clone(),
+                }
+            })?;
+            let parameters = Parameters {
+                stage,
+                kind: &stage_config.kind,
+                values: &stage_config.parameters,
+                asked: RefCell::new(BTreeSet::new()),
+            };
+            let processor = factory(&parameters)?;
+            if let Some(name) = parameters.unknown() {
+                return Err(ConfigError::UnknownParameter {
+                    stage,
+                    kind: stage_config.kind.clone(),
+                    name: name.to_owned(),
+                });
+            }
+            pipeline.processors.push(processor);
+        }
+        Ok(pipeline)
+    }
+}
+
+impl<D> Default for Registry<D>
+where
+    D: Data + 'static,
+{
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Syntax(toml::de::Error),
+    UnknownKind {
+        stage: usize,
+        kind: String,
+    },
+    MissingParameter {
+        stage: usize,
+        kind: String,
+        name: String,
+    },
+    BadParameter {
+        stage: usize,
+        kind: String,
+        name: String,
+        value: String,
+        reason: String,
+    },
+    UnknownParameter {
+        stage: usize,
+        kind: String,
+        name: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Syntax(ref e) => write!(f, "invalid pipeline config: {}", e),
+            ConfigError::UnknownKind { stage, ref kind } => {
+                write!(f, "stage {}: unknown kind \"{}\"", stage, kind)
+            }
+            ConfigError::MissingParameter {
+                stage,
+                ref kind,
+                ref name,
+            } => write!(f, "stage {} ({}): missing parameter \"{}\"", stage, kind, name),
+            ConfigError::BadParameter {
+                stage,
+                ref kind,
+                ref name,
+                ref value,
+                ref reason,
+            } => write!(
+                f,
+                "stage {} ({}): bad value \"{}\" for parameter \"{}\": {}",
+                stage, kind, value, name, reason
+            ),
+            ConfigError::UnknownParameter {
+                stage,
+                ref kind,
+                ref name,
+            } => write!(f, "stage {} ({}): unknown parameter \"{}\"", stage, kind, name),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+#[cfg(test)]
+mod test {
+    use drafts::pipeline_config::{ConfigError, Registry};
+    use drafts::recursive_template::test::{PhysicsProcessor, RenderProcessor};
+    use drafts::recursive_template::{Chain, Processor};
+
+    struct AiProcessor {
+        depth: u32,
+    }
+
+    impl Processor<String> for AiProcessor {
+        fn process(&mut self, data: String) -> String {
+            (0..self.depth).fold(data, |data, _| data + " => thinking!")
+        }
+    }
+
+    fn registry() -> Registry<String> {
+        Registry::new()
+            .register("physics", |_| Ok(PhysicsProcessor {}))
+            .register("ai", |p| Ok(AiProcessor { depth: p.get_or("depth", 1)? }))
+            .register("render", |p| Ok(RenderProcessor { screen: p.get("screen")? }))
+    }
+
+    #[test]
+    fn load_chain() {
+        let mut c = registry()
+            .load(
+                r#"
+                [[stage]]
+                kind = "physics"
+
+                [[stage]]
+                kind = "ai"
+
+                [[stage]]
+                kind = "render"
+                screen = "1"
+
+                [[stage]]
+                kind = "render"
+                screen = 2
+                "#,
+            )
+            .unwrap();
+        assert_eq!(c.len(), 4);
+        assert_eq!(
+            c.process("chobis".into()),
+            "chobis => physics! => thinking! => rendering in screen 1! => rendering in screen 2!"
+        );
+    }
+
+    #[test]
+    fn unknown_kind() {
+        let err = registry()
+            .load("[[stage]]\nkind = \"physics\"\n[[stage]]\nkind = \"audio\"\n")
+            .err()
+            .unwrap();
+        match err {
+            ConfigError::UnknownKind { stage: 1, ref kind } if kind == "audio" => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(err.to_string(), "stage 1: unknown kind \"audio\"");
+    }
+
+    #[test]
+    fn missing_and_bad_parameters() {
+        match registry().load("[[stage]]\nkind = \"render\"\n").err().unwrap() {
+            ConfigError::MissingParameter { stage: 0, ref name, .. } if name == "screen" => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        match registry().load("[[stage]]\nkind = \"ai\"\ndepth = \"deep\"\n").err().unwrap() {
+            ConfigError::BadParameter { stage: 0, ref name, ref value, .. } => {
+                assert_eq!(name, "depth");
+                assert_eq!(value, "deep");
+            }
+            ref other => panic!("unexpected error: {}", other),
+        }
+        match registry().load("[[stage]\nkind = \"ai\"\n").err().unwrap() {
+            ConfigError::Syntax(_) => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_parameters() {
+        // Even one the factory would have defaulted
+        let err = registry()
+            .load("[[stage]]\nkind = \"ai\"\ndepht = 3\n")
+            .err()
+            .unwrap();
+        match err {
+            ConfigError::UnknownParameter { stage: 0, ref name, .. } if name == "depht" => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+        assert_eq!(err.to_string(), "stage 0 (ai): unknown parameter \"depht\"");
+        match registry().load("[[stage]]\nkind = \"physics\"\nscreen = 1\n").err().unwrap() {
+            ConfigError::UnknownParameter { stage: 0, ref name, .. } if name == "screen" => (),
+            ref other => panic!("unexpected error: {}", other),
+        }
+    }
+}
@@ -1,8 +1,16 @@
+extern crate image;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
 extern crate nalgebra;
 
+/// The drafts this scene shares with the library, built from the same files.
+#[path = "."]
+#[allow(dead_code)]
+mod drafts {
+    pub mod software_backend;
+}
+
 use mursten::{Application, Backend, Data, Renderer, Updater};
 use mursten_blocks::camera::{Camera, GetCamera, CameraUpdater};
 use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
@@ -13,6 +21,9 @@ use mursten_blocks::repl::create_repl;
 use mursten_blocks::time::{Clock, ClockUpdater, OnTick, Tick};
 use mursten_vulkan_backend::{Uniforms, VulkanBackend};
 
+use drafts::software_backend::{software_backend_from_args, SoftwareCameraUpdater,
+                               SoftwareMeshRenderer};
+
 use nalgebra::*;
 use std::thread;
 
@@ -24,15 +35,26 @@ pub fn main() {
     //    repl_client.run();
     //});
 
-    let backend = VulkanBackend::new();
     let scene = Scene::new();
-    Application::new(backend)
-        .add_updater(ClockUpdater::new())
-        .add_updater(MidiUpdater::prompt())
-        .add_updater(CameraUpdater::new())
-        //.add_updater(repl_server)
-        .add_renderer(MeshRenderer::new())
-        .run(scene);
+    match software_backend_from_args() {
+        Some(backend) => {
+            // Nobody is there to pick a MIDI device
+            Application::new(backend)
+                .add_updater(ClockUpdater::new())
+                .add_updater(SoftwareCameraUpdater)
+                .add_renderer(SoftwareMeshRenderer)
+                .run(scene);
+        }
+        None => {
+            Application::new(VulkanBackend::new())
+                .add_updater(ClockUpdater::new())
+                .add_updater(MidiUpdater::prompt())
+                .add_updater(CameraUpdater::new())
+                //.add_updater(repl_server)
+                .add_renderer(MeshRenderer::new())
+                .run(scene);
+        }
+    }
 
     //repl_thread.join();
 }
@@ -1,49 +1,114 @@
+use mursten;
+use mursten::{Renderer, Updater};
+use std::panic;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread::{self, JoinHandle};
+
 pub trait Data {}
 
-pub trait Processor<D>
+/// A step of a chain. It may turn its input into a different kind of data;
+/// `Processor<D>` is the plain case where it doesn't.
+pub trait Processor<I, O = I>
 where
-    D: Data,
+    I: Data,
+    O: Data,
 {
-    fn process(&mut self, D) -> D;
+    fn process(&mut self, I) -> O;
 }
 
-pub trait Chain<D>
+pub trait Chain<I, O = I>
 where
-    D: Data,
+    I: Data,
+    O: Data,
     Self: Sized,
 {
-    fn process(&mut self, D) -> D;
-    fn add<P: Processor<D> + Sized>(self, processor: P) -> Node<D, P, Self> {
+    fn process(&mut self, I) -> O;
+    /// Appends a processor that takes this chain's output.
+    ///
+    /// ```
+    /// use mursten_experiments::drafts::recursive_template::{Chain, Data, Processor, System};
+    ///
+    /// struct Input;
+    /// struct State;
+    /// struct Frame;
+    /// impl Data for Input {}
+    /// impl Data for State {}
+    /// impl Data for Frame {}
+    ///
+    /// struct Physics;
+    /// impl Processor<Input, State> for Physics {
+    ///     fn process(&mut self, _: Input) -> State { State }
+    /// }
+    ///
+    /// struct Render;
+    /// impl Processor<State, Frame> for Render {
+    ///     fn process(&mut self, _: State) -> Frame { Frame }
+    /// }
+    ///
+    /// let _: Frame = System.add(Physics).add(Render).process(Input);
+    /// ```
+    ///
+    /// The processor's input must match what the chain produces:
+    ///
+    /// ```compile_fail,E0277
+    /// use mursten_experiments::drafts::recursive_template::{Chain, Data, Processor, System};
+    ///
+    /// struct Input;
+    /// struct State;
+    /// struct Frame;
+    /// impl Data for Input {}
+    /// impl Data for State {}
+    /// impl Data for Frame {}
+    ///
+    /// struct Physics;
+    /// impl Processor<Input, State> for Physics {
+    ///     fn process(&mut self, _: Input) -> State { State }
+    /// }
+    ///
+    /// struct Render;
+    /// impl Processor<State, Frame> for Render {
+    ///     fn process(&mut self, _: State) -> Frame { Frame }
+    /// }
+    ///
+    /// // Render expects a `State`, not an `Input`.
+    /// let _ = System.add(Render).add(Physics);
+    /// ```
+    fn add<N, P>(self, processor: P) -> Node<I, O, N, P, Self>
+    where
+        N: Data,
+        P: Processor<O, N> + Sized,
+    {
         Node {
-            next: Some(self),
+            next: self,
             processor,
             phantom_data: None,
         }
     }
 }
 
-pub struct Node<D, P, C>
+pub struct Node<I, M, O, P, C>
 where
-    D: Data,
-    P: Processor<D>,
-    C: Chain<D>,
+    I: Data,
+    M: Data,
+    O: Data,
+    P: Processor<M, O>,
+    C: Chain<I, M>,
 {
-    next: Option<C>,
+    next: C,
     processor: P,
-    phantom_data: Option<Box<D>>,
+    phantom_data: Option<Box<(I, M, O)>>,
 }
 
-impl<D, P, C> Chain<D> for Node<D, P, C>
+impl<I, M, O, P, C> Chain<I, O> for Node<I, M, O, P, C>
 where
-    D: Data,
-    P: Processor<D>,
-    C: Chain<D>,
+    I: Data,
+    M: Data,
+    O: Data,
+    P: Processor<M, O>,
+    C: Chain<I, M>,
 {
-    fn process(&mut self, data: D) -> D {
-        let data = match self.next {
-            Some(ref mut node) => node.process(data),
-            None => data,
-        };
+    fn process(&mut self, data: I) -> O {
+        let data = self.next.process(data);
         self.processor.process(data)
     }
 }
@@ -61,13 +126,377 @@ where
 
 ////////////////////////////////
 
+/// Hooks that run around every processor of a chain, for things like logging,
+/// checking invariants or diffing the data without touching the processors.
+pub trait Interceptor<D>
+where
+    D: Data,
+{
+    fn before(&mut self, _data: &D) {}
+    /// Wraps the call to the processor, which runs when `proceed` is called.
+    fn around(&mut self, data: D, proceed: &mut FnMut(D) -> D) -> D {
+        proceed(data)
+    }
+    fn after(&mut self, _data: &D) {}
+}
+
+impl<'a, D, X> Interceptor<D> for &'a mut X
+where
+    D: Data,
+    X: Interceptor<D> + ?Sized,
+{
+    fn before(&mut self, data: &D) {
+        (**self).before(data)
+    }
+    fn around(&mut self, data: D, proceed: &mut FnMut(D) -> D) -> D {
+        (**self).around(data, proceed)
+    }
+    fn after(&mut self, data: &D) {
+        (**self).after(data)
+    }
+}
+
+/// Runs the first interceptor outside of the second one.
+impl<D, A, B> Interceptor<D> for (A, B)
+where
+    D: Data,
+    A: Interceptor<D>,
+    B: Interceptor<D>,
+{
+    fn before(&mut self, data: &D) {
+        self.0.before(data);
+        self.1.before(data);
+    }
+    fn around(&mut self, data: D, proceed: &mut FnMut(D) -> D) -> D {
+        let inner = &mut self.1;
+        self.0
+            .around(data, &mut |data| inner.around(data, &mut *proceed))
+    }
+    fn after(&mut self, data: &D) {
+        self.1.after(data);
+        self.0.after(data);
+    }
+}
+
+pub trait InterceptableChain<D>: Chain<D>
+where
+    D: Data,
+{
+    fn process_intercepted(&mut self, D, &mut Interceptor<D>) -> D;
+    /// Wraps every processor added so far with `interceptor`. Processors added
+    /// afterwards aren't intercepted.
+    fn intercept<X>(self, interceptor: X) -> Intercepted<D, Self, X>
+    where
+        X: Interceptor<D>,
+    {
+        Intercepted {
+            chain: self,
+            interceptor,
+            phantom_data: None,
+        }
+    }
+}
+
+impl<D, P, C> InterceptableChain<D> for Node<D, D, D, P, C>
+where
+    D: Data,
+    P: Processor<D>,
+    C: InterceptableChain<D>,
+{
+    fn process_intercepted(&mut self, data: D, interceptor: &mut Interceptor<D>) -> D {
+        let data = self.next.process_intercepted(data, interceptor);
+        let processor = &mut self.processor;
+        interceptor.before(&data);
+        let data = interceptor.around(data, &mut |data| processor.process(data));
+        interceptor.after(&data);
+        data
+    }
+}
+
+impl<D> InterceptableChain<D> for System
+where
+    D: Data,
+{
+    fn process_intercepted(&mut self, data: D, _: &mut Interceptor<D>) -> D {
+        data
+    }
+}
+
+pub struct Intercepted<D, C, X>
+where
+    D: Data,
+    C: InterceptableChain<D>,
+    X: Interceptor<D>,
+{
+    chain: C,
+    interceptor: X,
+    phantom_data: Option<Box<D>>,
+}
+
+impl<D, C, X> Chain<D> for Intercepted<D, C, X>
+where
+    D: Data,
+    C: InterceptableChain<D>,
+    X: Interceptor<D>,
+{
+    fn process(&mut self, data: D) -> D {
+        self.chain.process_intercepted(data, &mut self.interceptor)
+    }
+}
+
+impl<D, C, X> InterceptableChain<D> for Intercepted<D, C, X>
+where
+    D: Data,
+    C: InterceptableChain<D>,
+    X: Interceptor<D>,
+{
+    fn process_intercepted(&mut self, data: D, outer: &mut Interceptor<D>) -> D {
+        self.chain
+            .process_intercepted(data, &mut (&mut self.interceptor, outer))
+    }
+}
+
+////////////////////////////////
+
+/// A chain whose processors each run on their own thread, handing data to the
+/// next one through a bounded channel.
+///
+/// A slow stage only blocks the stages before it once its channel is full, so
+/// producers keep going as long as there is room to buffer their output.
+///
+/// A stage that panics ends the stream, and the panic carries on in whoever
+/// reads the stream once the items before it have been read.
+pub trait StreamChain<I, O = I>
+where
+    I: Data + Send + 'static,
+    O: Data + Send + 'static,
+    Self: Chain<I, O> + Send + 'static,
+{
+    /// Starts the stages, reading from `input`, and adds their threads to
+    /// `stages`. Each stage buffers at most `bound` processed items.
+    fn spawn(self, input: Receiver<I>, bound: usize, stages: &mut Vec<JoinHandle<()>>)
+        -> Receiver<O>;
+
+    /// Feeds `input` through the chain. Items come out in the order they went in.
+    fn process_stream<S>(self, input: S, bound: usize) -> Stream<O>
+    where
+        S: IntoIterator<Item = I>,
+        S::IntoIter: Send + 'static,
+    {
+        let (sender, receiver) = sync_channel(bound);
+        let items = input.into_iter();
+        let mut stages = vec![thread::spawn(move || {
+            for data in items {
+                if sender.send(data).is_err() {
+                    break;
+                }
+            }
+        })];
+        Stream {
+            receiver: self.spawn(receiver, bound, &mut stages),
+            stages,
+        }
+    }
+}
+
+impl<I, M, O, P, C> StreamChain<I, O> for Node<I, M, O, P, C>
+where
+    I: Data + Send + 'static,
+    M: Data + Send + 'static,
+    O: Data + Send + 'static,
+    P: Processor<M, O> + Send + 'static,
+    C: StreamChain<I, M>,
+{
+    fn spawn(self, input: Receiver<I>, bound: usize, stages: &mut Vec<JoinHandle<()>>)
+        -> Receiver<O>
+    {
+        let Node {
+            next,
+            mut processor,
+            ..
+        } = self;
+        let input = next.spawn(input, bound, stages);
+        let (sender, output) = sync_channel(bound);
+        stages.push(thread::spawn(move || {
+            for data in input {
+                if sender.send(processor.process(data)).is_err() {
+                    break;
+                }
+            }
+        }));
+        output
+    }
+}
+
+impl<D> StreamChain<D> for System
+where
+    D: Data + Send + 'static,
+{
+    fn spawn(self, input: Receiver<D>, _: usize, _: &mut Vec<JoinHandle<()>>) -> Receiver<D> {
+        input
+    }
+}
+
+/// The output of `StreamChain::process_stream`.
+pub struct Stream<D> {
+    receiver: Receiver<D>,
+    stages: Vec<JoinHandle<()>>,
+}
+
+impl<D> Iterator for Stream<D> {
+    type Item = D;
+    fn next(&mut self) -> Option<D> {
+        match self.receiver.recv() {
+            Ok(data) => Some(data),
+            Err(_) => {
+                // Every stage has stopped, either at the end of the input or
+                // because one of them panicked
+                for stage in self.stages.drain(..) {
+                    if let Err(panic) = stage.join() {
+                        panic::resume_unwind(panic);
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+////////////////////////////////
+
+/// A step that works on the data in place, with the backend the application
+/// runs on at hand, the way mursten updaters and renderers do.
+pub trait ProcessorMut<B, D> {
+    fn process_mut(&mut self, backend: &mut B, data: &mut D);
+}
+
+/// A chain of `ProcessorMut`s. Nothing is moved in or out, so it can run over
+/// the application data as a mursten `Updater`.
+pub trait ChainMut<B, D>
+where
+    Self: Sized,
+{
+    fn process_mut(&mut self, backend: &mut B, data: &mut D);
+    fn add_mut<P>(self, processor: P) -> NodeMut<B, D, P, Self>
+    where
+        P: ProcessorMut<B, D>,
+    {
+        NodeMut {
+            next: self,
+            processor,
+            phantom_data: None,
+        }
+    }
+    fn into_updater(self) -> ChainUpdater<Self> {
+        ChainUpdater::new(self)
+    }
+}
+
+pub struct NodeMut<B, D, P, C> {
+    next: C,
+    processor: P,
+    phantom_data: Option<Box<(B, D)>>,
+}
+
+impl<B, D, P, C> ChainMut<B, D> for NodeMut<B, D, P, C>
+where
+    P: ProcessorMut<B, D>,
+    C: ChainMut<B, D>,
+{
+    fn process_mut(&mut self, backend: &mut B, data: &mut D) {
+        self.next.process_mut(backend, data);
+        self.processor.process_mut(backend, data);
+    }
+}
+
+impl<B, D> ChainMut<B, D> for System {
+    fn process_mut(&mut self, _: &mut B, _: &mut D) {}
+}
+
+/// Runs a chain as a mursten `Updater` over the application data, handing
+/// every step the application's backend.
+pub struct ChainUpdater<C> {
+    chain: C,
+}
+
+impl<C> ChainUpdater<C> {
+    pub fn new(chain: C) -> Self {
+        ChainUpdater { chain }
+    }
+}
+
+impl<B, D, C> Updater<B, D> for ChainUpdater<C>
+where
+    D: mursten::Data,
+    C: ChainMut<B, D>,
+{
+    fn update(&mut self, backend: &mut B, data: &mut D) {
+        self.chain.process_mut(backend, data);
+    }
+}
+
+/// Runs a mursten `Updater` as a step of a chain.
+pub struct UpdaterProcessor<U> {
+    updater: U,
+}
+
+impl<U> UpdaterProcessor<U> {
+    pub fn new(updater: U) -> Self {
+        UpdaterProcessor { updater }
+    }
+}
+
+impl<B, D, U> ProcessorMut<B, D> for UpdaterProcessor<U>
+where
+    D: mursten::Data,
+    U: Updater<B, D>,
+{
+    fn process_mut(&mut self, backend: &mut B, data: &mut D) {
+        self.updater.update(backend, data);
+    }
+}
+
+/// Runs a mursten `Renderer` as a step of a chain, leaving the data untouched.
+pub struct RendererProcessor<R> {
+    renderer: R,
+}
+
+impl<R> RendererProcessor<R> {
+    pub fn new(renderer: R) -> Self {
+        RendererProcessor { renderer }
+    }
+}
+
+impl<B, D, R> ProcessorMut<B, D> for RendererProcessor<R>
+where
+    D: mursten::Data,
+    R: Renderer<B, D>,
+{
+    fn process_mut(&mut self, backend: &mut B, data: &mut D) {
+        self.renderer.render(backend, data);
+    }
+}
+
+////////////////////////////////
+
 #[cfg(test)]
-mod test {
-    use drafts::recursive_template::{Chain, Data, Processor, System};
+pub mod test {
+    use drafts::recursive_template::{
+        Chain, ChainMut, Data, InterceptableChain, Interceptor, Processor, ProcessorMut,
+        RendererProcessor, StreamChain, System, UpdaterProcessor,
+    };
+    use mursten;
+    use mursten::{Renderer, Updater};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc::{channel, Receiver, TryRecvError};
+    use std::thread;
+    use std::time::Duration;
 
     impl Data for String {}
 
-    struct PhysicsProcessor {}
+    /// Shared with the pipeline_config tests.
+    pub struct PhysicsProcessor {}
 
     impl Processor<String> for PhysicsProcessor {
         fn process(&mut self, data: String) -> String {
@@ -75,8 +504,8 @@ mod test {
         }
     }
 
-    struct RenderProcessor {
-        screen: String,
+    pub struct RenderProcessor {
+        pub screen: String,
     }
 
     impl Processor<String> for RenderProcessor {
@@ -105,4 +534,309 @@ mod test {
             "chobis => physics! => thinking! => rendering in screen 1! => rendering in screen 2!"
         );
     }
+
+    struct Recorder {
+        name: &'static str,
+        log: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl Interceptor<String> for Recorder {
+        fn before(&mut self, data: &String) {
+            self.log.borrow_mut().push(format!("{} before: {}", self.name, data));
+        }
+        fn around(&mut self, data: String, proceed: &mut FnMut(String) -> String) -> String {
+            self.log.borrow_mut().push(format!("{} enter", self.name));
+            let data = proceed(data);
+            self.log.borrow_mut().push(format!("{} exit", self.name));
+            data
+        }
+        fn after(&mut self, data: &String) {
+            self.log.borrow_mut().push(format!("{} after: {}", self.name, data));
+        }
+    }
+
+    #[test]
+    fn intercept_chain() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut c = System
+            .add(PhysicsProcessor {})
+            .add(AiProcessor {})
+            .intercept(Recorder {
+                name: "log",
+                log: log.clone(),
+            })
+            .add(RenderProcessor { screen: "1".into() });
+        assert_eq!(
+            c.process("c".into()),
+            "c => physics! => thinking! => rendering in screen 1!"
+        );
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "log before: c",
+                "log enter",
+                "log exit",
+                "log after: c => physics!",
+                "log before: c => physics!",
+                "log enter",
+                "log exit",
+                "log after: c => physics! => thinking!",
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_interceptors() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut c = System
+            .add(PhysicsProcessor {})
+            .intercept(Recorder {
+                name: "first",
+                log: log.clone(),
+            })
+            .intercept(Recorder {
+                name: "second",
+                log: log.clone(),
+            });
+        assert_eq!(c.process("c".into()), "c => physics!");
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                "first before: c",
+                "second before: c",
+                "first enter",
+                "second enter",
+                "second exit",
+                "first exit",
+                "second after: c => physics!",
+                "first after: c => physics!",
+            ]
+        );
+    }
+
+    struct RawInput {
+        keys: Vec<char>,
+    }
+
+    struct PhysicsState {
+        position: i32,
+    }
+
+    struct RenderList {
+        sprites: Vec<String>,
+    }
+
+    impl Data for RawInput {}
+    impl Data for PhysicsState {}
+    impl Data for RenderList {}
+
+    struct InputProcessor {}
+
+    impl Processor<RawInput, PhysicsState> for InputProcessor {
+        fn process(&mut self, input: RawInput) -> PhysicsState {
+            let position = input
+                .keys
+                .iter()
+                .map(|k| match *k {
+                    'a' => -1,
+                    'd' => 1,
+                    _ => 0,
+                })
+                .sum();
+            PhysicsState { position }
+        }
+    }
+
+    struct StepProcessor {}
+
+    impl Processor<PhysicsState> for StepProcessor {
+        fn process(&mut self, state: PhysicsState) -> PhysicsState {
+            PhysicsState {
+                position: state.position * 10,
+            }
+        }
+    }
+
+    struct SpriteProcessor {
+        screen: String,
+    }
+
+    impl Processor<PhysicsState, RenderList> for SpriteProcessor {
+        fn process(&mut self, state: PhysicsState) -> RenderList {
+            RenderList {
+                sprites: vec![format!("player at {} in screen {}", state.position, self.screen)],
+            }
+        }
+    }
+
+    #[test]
+    fn create_typed_chain() {
+        let mut c = System
+            .add(InputProcessor {})
+            .add(StepProcessor {})
+            .add(SpriteProcessor { screen: "1".into() });
+        let frame = c.process(RawInput {
+            keys: vec!['d', 'd', 'a', 'd'],
+        });
+        assert_eq!(frame.sprites, vec!["player at 20 in screen 1"]);
+    }
+
+    struct SlowProcessor {
+        name: String,
+        delay: u64,
+    }
+
+    impl Processor<String> for SlowProcessor {
+        fn process(&mut self, data: String) -> String {
+            thread::sleep(Duration::from_millis(self.delay));
+            data + " => " + &self.name
+        }
+    }
+
+    struct GateProcessor {
+        open: Receiver<()>,
+    }
+
+    impl Processor<String> for GateProcessor {
+        fn process(&mut self, data: String) -> String {
+            self.open.recv().unwrap();
+            data
+        }
+    }
+
+    #[test]
+    fn stream_keeps_order() {
+        let output: Vec<String> = System
+            .add(SlowProcessor {
+                name: "network".into(),
+                delay: 5,
+            })
+            .add(SlowProcessor {
+                name: "render".into(),
+                delay: 1,
+            })
+            .process_stream((0..10).map(|i| i.to_string()), 2)
+            .collect();
+        let expected: Vec<String> = (0..10).map(|i| format!("{} => network => render", i)).collect();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn producer_blocks_when_buffer_is_full() {
+        let bound = 3;
+        let (produced, counter) = channel();
+        let (open, gate) = channel();
+        let stream = System.add(GateProcessor { open: gate }).process_stream(
+            (0..10).map(|i| i.to_string()).inspect(move |_| {
+                produced.send(()).unwrap();
+            }),
+            bound,
+        );
+
+        // The gate holds one item, the channel buffers `bound` more and the
+        // producer blocks while sending the next one.
+        for _ in 0..bound + 2 {
+            counter.recv().unwrap();
+        }
+        assert_eq!(counter.try_recv(), Err(TryRecvError::Empty));
+
+        for _ in 0..10 {
+            open.send(()).unwrap();
+        }
+        let output: Vec<String> = stream.collect();
+        let expected: Vec<String> = (0..10).map(|i| i.to_string()).collect();
+        assert_eq!(output, expected);
+        assert_eq!(counter.iter().count(), 10 - (bound + 2));
+    }
+
+    struct FaultyProcessor;
+
+    impl Processor<String> for FaultyProcessor {
+        fn process(&mut self, data: String) -> String {
+            if data == "3" {
+                panic!("can't process {}", data);
+            }
+            data
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "can't process 3")]
+    fn panicking_stage_fails_the_stream() {
+        let mut stream = System
+            .add(FaultyProcessor)
+            .add(SlowProcessor {
+                name: "render".into(),
+                delay: 1,
+            })
+            .process_stream((0..10).map(|i| i.to_string()), 2);
+        for i in 0..3 {
+            assert_eq!(stream.next(), Some(format!("{} => render", i)));
+        }
+        stream.next();
+    }
+
+    // No `Default`: the chain never needs to move the scene out
+    struct Scene {
+        height: f32,
+        steps: Vec<String>,
+    }
+
+    impl mursten::Data for Scene {}
+
+    /// A backend that keeps what was drawn on it.
+    struct Canvas {
+        frames: Vec<String>,
+    }
+
+    struct Gravity;
+
+    impl<B> Updater<B, Scene> for Gravity {
+        fn update(&mut self, _: &mut B, scene: &mut Scene) {
+            scene.height -= 1.0;
+            scene.steps.push(format!("physics at {}", scene.height));
+        }
+    }
+
+    struct Wander;
+
+    impl<B> ProcessorMut<B, Scene> for Wander {
+        fn process_mut(&mut self, _: &mut B, scene: &mut Scene) {
+            scene.steps.push("thinking".into());
+        }
+    }
+
+    struct Screen;
+
+    impl Renderer<Canvas, Scene> for Screen {
+        fn render(&mut self, canvas: &mut Canvas, scene: &Scene) {
+            assert_eq!(scene.steps.last().unwrap(), "thinking");
+            canvas.frames.push(format!("scene at {}", scene.height));
+        }
+    }
+
+    #[test]
+    fn chain_as_updater() {
+        let mut updater = System
+            .add_mut(UpdaterProcessor::new(Gravity))
+            .add_mut(Wander)
+            .add_mut(RendererProcessor::new(Screen))
+            .into_updater();
+        let mut canvas = Canvas { frames: Vec::new() };
+        let mut scene = Scene {
+            height: 0.0,
+            steps: Vec::new(),
+        };
+
+        updater.update(&mut canvas, &mut scene);
+        updater.update(&mut canvas, &mut scene);
+
+        assert_eq!(scene.height, -2.0);
+        assert_eq!(
+            scene.steps,
+            vec!["physics at -1", "thinking", "physics at -2", "thinking"]
+        );
+        // The renderer drew on the backend the updater was given
+        assert_eq!(canvas.frames, vec!["scene at -1", "scene at -2"]);
+    }
 }
@@ -1,7 +1,35 @@
+extern crate image;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
 extern crate nalgebra;
+extern crate reqwest;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate toml;
+
+/// The drafts this scene shares with the library, built from the same files.
+#[path = "."]
+#[allow(dead_code)]
+mod drafts {
+    #[cfg(test)]
+    pub mod golden;
+    pub mod software_backend;
+    pub mod visuals {
+        pub mod equations;
+    }
+    pub mod weather;
+    pub mod weather_cache;
+    pub mod weather_fetcher;
+    pub mod weather_format;
+    pub mod weather_history;
+    pub mod weather_location;
+    pub mod weather_providers;
+    pub mod weather_scene;
+    pub mod weather_units;
+}
 
 use mursten::{Application, Backend, Data};
 
@@ -14,21 +42,46 @@ use mursten_blocks::light::{Light, GetLights, LightUpdater};
 
 use mursten_vulkan_backend::VulkanBackend;
 
+use drafts::software_backend::{software_backend_from_args, SoftwareCameraUpdater,
+                               SoftwareMeshRenderer};
+use drafts::weather_scene::{weather_updater_from_env, OnWeather, WeatherLook};
+
 use nalgebra::*;
 use std::f32::consts::PI;
 use std::process::{Command, Stdio};
 
 
 pub fn main() {
-    let backend = VulkanBackend::new();
     let scene = Scene::new();
-    Application::new(backend)
-        .add_updater(ClockUpdater::new())
-        .add_updater(CameraUpdater::new())
-        .add_updater(KeyboardUpdater::new())
-        .add_updater(MouseUpdater::new())
-        .add_renderer(MeshRenderer::new())
-        .run(scene);
+    match software_backend_from_args() {
+        Some(backend) => run(
+            Application::new(backend)
+                .add_updater(ClockUpdater::new())
+                .add_updater(SoftwareCameraUpdater)
+                .add_renderer(SoftwareMeshRenderer),
+            scene,
+        ),
+        None => run(
+            Application::new(VulkanBackend::new())
+                .add_updater(ClockUpdater::new())
+                .add_updater(CameraUpdater::new())
+                .add_updater(KeyboardUpdater::new())
+                .add_updater(MouseUpdater::new())
+                .add_renderer(MeshRenderer::new()),
+            scene,
+        ),
+    }
+}
+
+fn run<B>(app: Application<B, Scene>, scene: Scene)
+where
+    B: Backend<Scene> + 'static,
+{
+    let app = match weather_updater_from_env() {
+        Some(weather) => app.add_updater(weather),
+        None => app,
+    };
+    app.run(scene);
 }
 
 struct Scene { 
@@ -40,6 +93,7 @@ struct Scene {
     desk: Desk,
     lamp: Lamp,
     painting: Painting,
+    light_intensity: f32,
 }
 
 struct Player {
@@ -394,12 +448,19 @@ impl Scene {
             desk: Desk::new(Point3::new(-2.0, 0.0, 0.0)),
             lamp: Lamp::new(Point3::new(-2.0, 1.0, 0.0)),
             painting: Painting::new(Point3::new(0.0, 0.0, 2.98)),
+            light_intensity: 0.3,
         }
     }
 }
 
 impl mursten::Data for Scene {}
 
+impl OnWeather for Scene {
+    fn on_weather(&mut self, look: WeatherLook) {
+        self.light_intensity = look.light_intensity;
+    }
+}
+
 impl OnTick for Scene {
     fn on_tick(&mut self, tick: Tick) {
         self.clock += tick;
@@ -498,7 +559,7 @@ impl GetCamera for Scene {
 impl GetLights for Scene {
     fn get_light(&self) -> Light {
         let p = Point3::origin() + Rotation3::from_axis_angle(&Vector3::y_axis(), self.clock.time_in_sec()) * Vector3::new(2.0, 3.0, 0.0);
-        Light::new(p, Vector3::new(0.0, 2.8, 0.0), 0.3)
+        Light::new(p, Vector3::new(0.0, 2.8, 0.0), self.light_intensity)
     }
 }
 
@@ -606,3 +667,21 @@ impl Into<Vector4<f32>> for Palette {
         Vector4::new(c.x, c.y, c.z, 1.0)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Scene;
+    use drafts::golden::{FixedClockUpdater, Golden, Harness};
+    use drafts::software_backend::{SoftwareCameraUpdater, SoftwareMeshRenderer};
+
+    #[test]
+    fn golden_frame() {
+        let mut scene = Scene::new();
+        let image = Harness::new(160, 120)
+            .add_updater(FixedClockUpdater::default())
+            .add_updater(SoftwareCameraUpdater)
+            .add_renderer(SoftwareMeshRenderer)
+            .render(&mut scene, 30);
+        Golden::new("room").assert_matches(&image);
+    }
+}
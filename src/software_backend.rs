@@ -0,0 +1,444 @@
+use image::{ImageResult, RgbaImage};
+use mursten::{Backend, Data, RenderChain, Renderer, UpdateChain, Updater};
+use mursten_blocks::camera::GetCamera;
+use mursten_blocks::geometry::{Mesh, Vertex};
+use mursten_blocks::mesh_renderer::GetMeshes;
+use mursten_vulkan_backend::{self, VulkanBackend};
+use nalgebra::*;
+use std::env;
+use std::f32::consts::PI;
+use std::f32::EPSILON;
+use std::fs;
+use std::path::PathBuf;
+
+/// Where vertices end up: `projection * view * transform * position`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Uniforms {
+    pub projection: Matrix4<f32>,
+    pub view: Matrix4<f32>,
+}
+
+impl Default for Uniforms {
+    fn default() -> Self {
+        Uniforms {
+            projection: Matrix4::identity(),
+            view: Matrix4::identity(),
+        }
+    }
+}
+
+/// A projection like `Camera::perspective()`'s: 60° vertically, from 0.1 to
+/// 1000 units away, looking down -z.
+pub fn perspective(aspect: f32) -> Matrix4<f32> {
+    Perspective3::new(aspect, PI / 3.0, 0.1, 1000.0).to_homogeneous()
+}
+
+/// What scenes that queue their own meshes need from a backend, so they can
+/// draw on the GPU or on the CPU.
+pub trait MeshBackend {
+    fn queue_render(&mut self, mesh: Mesh);
+    fn set_uniforms(&mut self, uniforms: Uniforms);
+}
+
+impl MeshBackend for VulkanBackend {
+    fn queue_render(&mut self, mesh: Mesh) {
+        VulkanBackend::queue_render(self, mesh);
+    }
+    fn set_uniforms(&mut self, uniforms: Uniforms) {
+        VulkanBackend::set_uniforms(
+            self,
+            mursten_vulkan_backend::Uniforms {
+                projection: uniforms.projection * uniforms.view,
+                ..mursten_vulkan_backend::Uniforms::default()
+            },
+        );
+    }
+}
+
+/// RGBA colours, from 0 to 1, and the depth of what was drawn, in NDC.
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    color: Vec<Vector4<f32>>,
+    depth: Vec<f32>,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let size = (width * height) as usize;
+        Framebuffer {
+            width,
+            height,
+            color: vec![Vector4::zeros(); size],
+            depth: vec![f32::INFINITY; size],
+        }
+    }
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+    pub fn clear(&mut self, color: Vector4<f32>) {
+        for pixel in self.color.iter_mut() {
+            *pixel = color;
+        }
+        for depth in self.depth.iter_mut() {
+            *depth = f32::INFINITY;
+        }
+    }
+    pub fn pixel(&self, x: u32, y: u32) -> Vector4<f32> {
+        self.color[(y * self.width + x) as usize]
+    }
+    pub fn depth(&self, x: u32, y: u32) -> f32 {
+        self.depth[(y * self.width + x) as usize]
+    }
+    /// Fills a triangle given in clip space, interpolating the vertex colours
+    /// with perspective correction and blending them by their alpha.
+    ///
+    /// Only opaque fragments write depth, so translucent triangles have to be
+    /// queued back to front, like on the GPU. Triangles reaching behind the
+    /// eye are skipped rather than clipped.
+    pub fn draw_triangle(&mut self, clip: [Vector4<f32>; 3], colors: [Vector4<f32>; 3]) {
+        if clip.iter().any(|p| p.w <= EPSILON) {
+            return;
+        }
+        let (w, h) = (self.width as f32, self.height as f32);
+        let screen: Vec<Vector3<f32>> = clip.iter()
+            .map(|p| {
+                let ndc = p.xyz() / p.w;
+                Vector3::new((ndc.x + 1.0) * 0.5 * w, (1.0 - ndc.y) * 0.5 * h, ndc.z)
+            })
+            .collect();
+        let edge = |a: &Vector3<f32>, b: &Vector3<f32>, x: f32, y: f32| {
+            (b.x - a.x) * (y - a.y) - (b.y - a.y) * (x - a.x)
+        };
+        let area = edge(&screen[0], &screen[1], screen[2].x, screen[2].y);
+        if area.abs() < EPSILON {
+            return;
+        }
+
+        let min_x = screen.iter().map(|p| p.x).fold(w, f32::min).max(0.0) as u32;
+        let max_x = screen.iter().map(|p| p.x).fold(0.0, f32::max).min(w - 1.0).ceil() as u32;
+        let min_y = screen.iter().map(|p| p.y).fold(h, f32::min).max(0.0) as u32;
+        let max_y = screen.iter().map(|p| p.y).fold(0.0, f32::max).min(h - 1.0).ceil() as u32;
+        for y in min_y..(max_y + 1).min(self.height) {
+            for x in min_x..(max_x + 1).min(self.width) {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                let b = [
+                    edge(&screen[1], &screen[2], px, py) / area,
+                    edge(&screen[2], &screen[0], px, py) / area,
+                    edge(&screen[0], &screen[1], px, py) / area,
+                ];
+                if b.iter().any(|&b| b < 0.0) {
+                    continue;
+                }
+                let z = b[0] * screen[0].z + b[1] * screen[1].z + b[2] * screen[2].z;
+                let i = (y * self.width + x) as usize;
+                if z < -1.0 || z > 1.0 || z >= self.depth[i] {
+                    continue;
+                }
+                let weights = [b[0] / clip[0].w, b[1] / clip[1].w, b[2] / clip[2].w];
+                let color = (colors[0] * weights[0] + colors[1] * weights[1]
+                    + colors[2] * weights[2])
+                    / (weights[0] + weights[1] + weights[2]);
+                let alpha = color.w.max(0.0).min(1.0);
+                let under = self.color[i];
+                let rgb = color.xyz() * alpha + under.xyz() * (1.0 - alpha);
+                self.color[i] = Vector4::new(rgb.x, rgb.y, rgb.z, alpha + under.w * (1.0 - alpha));
+                if alpha >= 1.0 {
+                    self.depth[i] = z;
+                }
+            }
+        }
+    }
+    pub fn to_image(&self) -> RgbaImage {
+        let bytes = self.color
+            .iter()
+            .flat_map(|c| {
+                let byte = |v: f32| (v.max(0.0).min(1.0) * 255.0).round() as u8;
+                vec![byte(c.x), byte(c.y), byte(c.z), byte(c.w)]
+            })
+            .collect();
+        RgbaImage::from_raw(self.width, self.height, bytes).unwrap()
+    }
+}
+
+/// Draws on the CPU into a framebuffer instead of a window, so scenes can run
+/// on machines without a GPU, optionally writing every frame as a PNG.
+///
+/// Meshes are drawn with their vertex colours, without lighting.
+pub struct SoftwareBackend {
+    framebuffer: Framebuffer,
+    background: Vector4<f32>,
+    uniforms: Uniforms,
+    queue: Vec<(Mesh, Matrix4<f32>)>,
+    output: Option<PathBuf>,
+    frames: Option<usize>,
+    frame: usize,
+    quit: bool,
+}
+
+impl SoftwareBackend {
+    pub fn new(width: u32, height: u32) -> Self {
+        SoftwareBackend {
+            framebuffer: Framebuffer::new(width, height),
+            background: Vector4::new(0.0, 0.0, 0.0, 1.0),
+            uniforms: Uniforms {
+                projection: perspective(width as f32 / height as f32),
+                view: Matrix4::identity(),
+            },
+            queue: Vec::new(),
+            output: None,
+            frames: None,
+            frame: 0,
+            quit: false,
+        }
+    }
+    pub fn background(self, background: Vector4<f32>) -> Self {
+        SoftwareBackend { background, ..self }
+    }
+    /// Writes each frame to `dir` as `frame-00000.png`, `frame-00001.png`...
+    pub fn output_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+        SoftwareBackend {
+            output: Some(dir.into()),
+            ..self
+        }
+    }
+    /// Quits after drawing `frames` frames, instead of running forever.
+    pub fn frames(self, frames: usize) -> Self {
+        SoftwareBackend {
+            frames: Some(frames),
+            ..self
+        }
+    }
+    /// Queues a mesh placed by `transform`, besides its own transform.
+    pub fn queue_transformed(&mut self, mesh: Mesh, transform: Matrix4<f32>) {
+        self.queue.push((mesh, transform));
+    }
+    pub fn uniforms(&self) -> Uniforms {
+        self.uniforms
+    }
+    pub fn framebuffer(&self) -> &Framebuffer {
+        &self.framebuffer
+    }
+    /// How many frames were drawn.
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+    /// Draws what was queued since the last frame.
+    pub fn draw(&mut self) {
+        self.framebuffer.clear(self.background);
+        let camera = self.uniforms.projection * self.uniforms.view;
+        for (mesh, transform) in self.queue.drain(..) {
+            let to_clip = camera * transform * mesh.transform.to_homogeneous();
+            for t in &mesh.triangles {
+                let clip = |v: &Vertex| to_clip * v.position.to_homogeneous();
+                self.framebuffer.draw_triangle(
+                    [clip(&t.v1), clip(&t.v2), clip(&t.v3)],
+                    [t.v1.color, t.v2.color, t.v3.color],
+                );
+            }
+        }
+        self.frame += 1;
+    }
+    pub fn save_frame(&self, path: &PathBuf) -> ImageResult<()> {
+        self.framebuffer.to_image().save(path).map_err(Into::into)
+    }
+    fn finish_frame(&mut self) -> ImageResult<()> {
+        self.draw();
+        if let Some(ref dir) = self.output {
+            fs::create_dir_all(dir)?;
+            self.save_frame(&dir.join(format!("frame-{:05}.png", self.frame - 1)))?;
+        }
+        if self.frames.map_or(false, |frames| self.frame >= frames) {
+            self.quit = true;
+        }
+        Ok(())
+    }
+}
+
+impl MeshBackend for SoftwareBackend {
+    fn queue_render(&mut self, mesh: Mesh) {
+        self.queue.push((mesh, Matrix4::identity()));
+    }
+    fn set_uniforms(&mut self, uniforms: Uniforms) {
+        self.uniforms = uniforms;
+    }
+}
+
+impl<D> Backend<D> for SoftwareBackend
+where
+    D: Data,
+{
+    fn run(
+        &mut self,
+        mut render_chain: RenderChain<Self, D>,
+        mut update_chain: UpdateChain<Self, D>,
+        mut data: D,
+    ) -> D {
+        while !self.quit {
+            update_chain.update(self, &mut data);
+            render_chain.render(self, &data);
+            if let Err(e) = self.finish_frame() {
+                eprintln!("software backend: can't write frame {}: {}", self.frame - 1, e);
+                self.quit = true;
+            }
+        }
+        data
+    }
+    fn quit(&mut self) {
+        self.quit = true;
+    }
+}
+
+/// Takes the view from the scene's camera every update, like `CameraUpdater`
+/// does for the GPU.
+pub struct SoftwareCameraUpdater;
+
+impl<D> Updater<SoftwareBackend, D> for SoftwareCameraUpdater
+where
+    D: Data + GetCamera,
+{
+    fn update(&mut self, backend: &mut SoftwareBackend, data: &mut D) {
+        let (view, _) = data.get_camera();
+        let uniforms = Uniforms {
+            view,
+            ..backend.uniforms()
+        };
+        backend.set_uniforms(uniforms);
+    }
+}
+
+/// Queues the scene's meshes, like `MeshRenderer` does for the GPU.
+pub struct SoftwareMeshRenderer;
+
+impl<D> Renderer<SoftwareBackend, D> for SoftwareMeshRenderer
+where
+    D: Data + GetMeshes,
+{
+    fn render(&mut self, backend: &mut SoftwareBackend, data: &D) {
+        for into_mesh in data.mesh_iter() {
+            backend.queue_transformed(into_mesh.mesh(), into_mesh.transform());
+        }
+    }
+}
+
+/// `--software` draws on the CPU instead of opening a window, `--frames N`
+/// frames (1 by default) of `--size WxH` (640x480 by default), written to
+/// `--out DIR` (`frames` by default).
+pub fn software_backend_from_args() -> Option<SoftwareBackend> {
+    let args: Vec<String> = env::args().collect();
+    if !args.iter().any(|a| a == "--software") {
+        return None;
+    }
+    let value = |flag: &str| {
+        args.iter()
+            .position(|a| a == flag)
+            .and_then(|i| args.get(i + 1))
+            .map(|v| v.as_str())
+    };
+    let (width, height) = value("--size")
+        .and_then(|size| {
+            let mut parts = size.splitn(2, 'x').map(|n| n.parse().ok());
+            Some((parts.next()??, parts.next()??))
+        })
+        .unwrap_or((640, 480));
+    let frames = value("--frames").and_then(|n| n.parse().ok()).unwrap_or(1);
+    Some(
+        SoftwareBackend::new(width, height)
+            .frames(frames)
+            .output_dir(value("--out").unwrap_or("frames")),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::software_backend::{perspective, Framebuffer, MeshBackend, SoftwareBackend,
+                                   Uniforms};
+    use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
+    use nalgebra::*;
+
+    fn close(a: Vector4<f32>, b: Vector4<f32>) -> bool {
+        (a - b).norm() < 0.02
+    }
+
+    #[test]
+    fn interpolates_vertex_colors() {
+        let mut framebuffer = Framebuffer::new(64, 64);
+        framebuffer.clear(Vector4::new(0.0, 0.0, 0.0, 1.0));
+        let red = Vector4::new(1.0, 0.0, 0.0, 1.0);
+        let green = Vector4::new(0.0, 1.0, 0.0, 1.0);
+        framebuffer.draw_triangle(
+            [
+                Vector4::new(-1.0, 1.0, 0.0, 1.0),
+                Vector4::new(-1.0, -1.0, 0.0, 1.0),
+                Vector4::new(1.0, 1.0, 0.0, 1.0),
+            ],
+            [red, red, green],
+        );
+        // Upper left half only, NDC y pointing up
+        assert!(close(framebuffer.pixel(0, 0), red));
+        assert!(close(framebuffer.pixel(63, 63), Vector4::new(0.0, 0.0, 0.0, 1.0)));
+        let middle = framebuffer.pixel(31, 0);
+        assert!((middle.x - 0.5).abs() < 0.05 && (middle.y - 0.5).abs() < 0.05);
+        assert_eq!(framebuffer.depth(0, 0), 0.0);
+        let image = framebuffer.to_image().into_raw();
+        assert_eq!(image.len(), 64 * 64 * 4);
+        assert_eq!(&image[image.len() - 4..], &[0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn depth_test_and_blending() {
+        let mut framebuffer = Framebuffer::new(8, 8);
+        framebuffer.clear(Vector4::new(0.0, 0.0, 0.0, 1.0));
+        let quad = |z: f32| {
+            [
+                Vector4::new(-1.0, -1.0, z, 1.0),
+                Vector4::new(3.0, -1.0, z, 1.0),
+                Vector4::new(-1.0, 3.0, z, 1.0),
+            ]
+        };
+        let near = Vector4::new(0.0, 0.0, 1.0, 1.0);
+        let far = Vector4::new(1.0, 1.0, 1.0, 1.0);
+        framebuffer.draw_triangle(quad(-0.5), [near; 3]);
+        framebuffer.draw_triangle(quad(0.5), [far; 3]);
+        assert!(close(framebuffer.pixel(4, 4), near));
+
+        // Half transparent red over blue, and nothing behind the eye
+        framebuffer.draw_triangle(quad(-0.9), [Vector4::new(1.0, 0.0, 0.0, 0.5); 3]);
+        assert!(close(framebuffer.pixel(4, 4), Vector4::new(0.5, 0.0, 0.5, 1.0)));
+        let mut behind = quad(0.0);
+        behind[0].w = -1.0;
+        framebuffer.draw_triangle(behind, [far; 3]);
+        assert!(close(framebuffer.pixel(4, 4), Vector4::new(0.5, 0.0, 0.5, 1.0)));
+    }
+
+    #[test]
+    fn draws_queued_meshes() {
+        let mut backend = SoftwareBackend::new(32, 32);
+        backend.set_uniforms(Uniforms {
+            projection: perspective(1.0),
+            view: Matrix4::identity(),
+        });
+        let white = |p| Vertex::at(p).color(1.0, 1.0, 1.0, 1.0);
+        backend.queue_render(Mesh {
+            triangles: vec![
+                Triangle::new(
+                    white(Point3::new(-1.0, -1.0, 0.0)),
+                    white(Point3::new(1.0, -1.0, 0.0)),
+                    white(Point3::new(0.0, 1.0, 0.0)),
+                ),
+            ],
+            transform: Transform3::identity() * Translation3::new(0.0, 0.0, -5.0),
+        });
+        backend.draw();
+        assert_eq!(backend.frame(), 1);
+        assert!(close(backend.framebuffer().pixel(16, 16), Vector4::repeat(1.0)));
+        assert!(close(backend.framebuffer().pixel(1, 1), Vector4::new(0.0, 0.0, 0.0, 1.0)));
+
+        // The queue empties every frame
+        backend.draw();
+        assert!(close(backend.framebuffer().pixel(16, 16), Vector4::new(0.0, 0.0, 0.0, 1.0)));
+    }
+}
@@ -1,9 +1,17 @@
+extern crate image;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
 extern crate nalgebra;
 extern crate rand;
 
+/// The drafts this scene shares with the library, built from the same files.
+#[path = "."]
+#[allow(dead_code)]
+mod drafts {
+    pub mod software_backend;
+}
+
 use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
 use mursten_blocks::camera::{Camera, CameraUpdater, GetCamera};
 use mursten_blocks::time::{Clock, ClockUpdater, OnTick, Tick};
@@ -12,20 +20,35 @@ use mursten_blocks::mesh_renderer::{GetMeshes, IntoMesh, MeshRenderer};
 use mursten_blocks::light::{Light, GetLights, LightUpdater};
 use mursten_vulkan_backend::VulkanBackend;
 
+use drafts::software_backend::{software_backend_from_args, SoftwareCameraUpdater,
+                               SoftwareMeshRenderer};
+
 use nalgebra::*;
 
 
+/// The software backend doesn't shade, so `--software` only shows the
+/// vertex colours.
 pub fn main() {
-    let backend = VulkanBackend::new();
     let scene = Scene::new();
-    mursten::Application::new(backend)
-        .add_updater(ClockUpdater::new())
-        .add_updater(CameraUpdater::new())
-        .add_updater(KeyboardUpdater::new())
-        .add_updater(MouseUpdater::new())
-        .add_updater(LightUpdater::new())
-        .add_renderer(MeshRenderer::new())
-        .run(scene);
+    match software_backend_from_args() {
+        Some(backend) => {
+            mursten::Application::new(backend)
+                .add_updater(ClockUpdater::new())
+                .add_updater(SoftwareCameraUpdater)
+                .add_renderer(SoftwareMeshRenderer)
+                .run(scene);
+        }
+        None => {
+            mursten::Application::new(VulkanBackend::new())
+                .add_updater(ClockUpdater::new())
+                .add_updater(CameraUpdater::new())
+                .add_updater(KeyboardUpdater::new())
+                .add_updater(MouseUpdater::new())
+                .add_updater(LightUpdater::new())
+                .add_renderer(MeshRenderer::new())
+                .run(scene);
+        }
+    }
 }
 
 struct Scene {
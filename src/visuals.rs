@@ -1,6 +1,5 @@
 extern crate graphics;
 extern crate image;
-extern crate markov;
 extern crate mursten;
 extern crate mursten_blocks;
 extern crate mursten_vulkan_backend;
@@ -11,14 +10,76 @@ extern crate reqwest;
 
 use mursten::{Application, Backend, Data, Renderer, Updater};
 use mursten_blocks::geometry::{Mesh, Triangle, Vertex};
-use mursten_vulkan_backend::{Uniforms, VulkanBackend};
+use mursten_blocks::input::{Key, KeyboardEvent, KeyboardUpdater, OnKeyboard};
+use mursten_blocks::midi::{MidiMessage, MidiUpdater, OnMidiMessage};
+use mursten_blocks::properties::{GetProperties, Properties};
+use mursten_vulkan_backend::VulkanBackend;
 use nalgebra::*;
+use rand::{Rng, StdRng};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use toml;
+
+use drafts::markov::seeded_rng;
+use drafts::software_backend::{software_backend_from_args, MeshBackend, Uniforms};
+use drafts::weather_scene::{weather_updater_from_env, OnWeather, WeatherLook};
+
+pub mod equations;
+
+/// Where the parameters are saved to and loaded from, unless `--preset` says
+/// otherwise.
+const PRESET_FILE: &str = "visuals.toml";
 
 pub fn main() {
-    let backend = VulkanBackend::new();
+    let (preset, midi) = controls_from_args();
     let mut variables = Variables::default();
-    Application::new(backend)
-        .add_updater(ColorRotator)
+    if preset.exists() {
+        match Preset::load(&preset) {
+            Ok(loaded) => variables.apply(&loaded),
+            Err(e) => eprintln!("can't load {}: {}", preset.display(), e),
+        }
+    }
+    variables.preset = preset;
+    match software_backend_from_args() {
+        Some(backend) => run(Application::new(backend), variables, midi),
+        None => run(
+            Application::new(VulkanBackend::new()).add_updater(KeyboardUpdater::new()),
+            variables,
+            midi,
+        ),
+    }
+}
+
+/// `--preset visuals.toml` to keep the parameters somewhere else, `--midi` to
+/// tune them from a MIDI device.
+fn controls_from_args() -> (PathBuf, bool) {
+    let args: Vec<String> = env::args().collect();
+    let preset = args.iter()
+        .position(|a| a == "--preset")
+        .and_then(|i| args.get(i + 1))
+        .map_or(PathBuf::from(PRESET_FILE), PathBuf::from);
+    (preset, args.iter().any(|a| a == "--midi"))
+}
+
+fn run<B>(app: Application<B, Variables>, variables: Variables, midi: bool)
+where
+    B: Backend<Variables> + MeshBackend + 'static,
+{
+    let app = if midi {
+        app.add_updater(MidiUpdater::prompt())
+    } else {
+        app
+    };
+    // The weather look owns the colour, rotating it would hide the temperature
+    let app = match weather_updater_from_env() {
+        Some(weather) => app.add_updater(weather),
+        None => app.add_updater(ColorRotator),
+    };
+    app.add_updater(KeepInRange)
         .add_renderer(Visual::new())
         .run(variables);
 }
@@ -27,10 +88,21 @@ struct Variables {
     center: Point2<f32>,
     separation: Vector2<f32>,
     matrix_size: Vector2<f32>,
+    /// Length of a ray over its width.
     ray_proportion: f32,
-    glow_amount: f32,     // < 0
-    cross_intensity: f32, // < 0
+    /// How far the faint edges of the rays glow, the higher the further.
+    glow_amount: f32,
+    /// How far from the axes rays reach, in cells: a ray `x` and `y` cells
+    /// off the centre fades to 1/e where `x * y` is this squared.
+    cross_intensity: f32,
     current_color: Vector3<f32>,
+    /// The parameter the keyboard and MIDI controls change.
+    selected: Parameter,
+    /// The last selected parameter printed.
+    shown: Option<Parameter>,
+    /// The last weather applied, to move from it to the next one.
+    weather: Option<WeatherLook>,
+    preset: PathBuf,
 }
 
 impl Variables {
@@ -52,12 +124,346 @@ impl Default for Variables {
             glow_amount: 5.0,
             cross_intensity: 6.0,
             current_color: Vector3::new(0.1, 0.6, 0.9),
+            selected: Parameter::RayProportion,
+            shown: None,
+            weather: None,
+            preset: PathBuf::from(PRESET_FILE),
         }
     }
 }
 
 impl Data for Variables {}
 
+/// A variable that can be tuned while the visuals run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Parameter {
+    CenterX,
+    CenterY,
+    SeparationX,
+    SeparationY,
+    MatrixWidth,
+    MatrixHeight,
+    RayProportion,
+    GlowAmount,
+    CrossIntensity,
+}
+
+/// In the order the controls go through them.
+const PARAMETERS: [Parameter; 9] = [
+    Parameter::RayProportion,
+    Parameter::GlowAmount,
+    Parameter::CrossIntensity,
+    Parameter::SeparationX,
+    Parameter::SeparationY,
+    Parameter::MatrixWidth,
+    Parameter::MatrixHeight,
+    Parameter::CenterX,
+    Parameter::CenterY,
+];
+
+impl Parameter {
+    /// Its name as a property.
+    fn name(self) -> &'static str {
+        match self {
+            Parameter::CenterX => "center.x",
+            Parameter::CenterY => "center.y",
+            Parameter::SeparationX => "separation.x",
+            Parameter::SeparationY => "separation.y",
+            Parameter::MatrixWidth => "matrix_size.x",
+            Parameter::MatrixHeight => "matrix_size.y",
+            Parameter::RayProportion => "ray_proportion",
+            Parameter::GlowAmount => "glow_amount",
+            Parameter::CrossIntensity => "cross_intensity",
+        }
+    }
+    /// The values the controls can take it to.
+    fn range(self) -> (f32, f32) {
+        match self {
+            Parameter::CenterX | Parameter::CenterY => (-1.0, 1.0),
+            Parameter::SeparationX | Parameter::SeparationY => (0.01, 0.2),
+            Parameter::MatrixWidth | Parameter::MatrixHeight => (1.0, 30.0),
+            Parameter::RayProportion => (1.0, 12.0),
+            Parameter::GlowAmount | Parameter::CrossIntensity => (0.5, 12.0),
+        }
+    }
+    /// The one `steps` places after it in `PARAMETERS`, wrapping around.
+    fn cycle(self, steps: isize) -> Parameter {
+        let len = PARAMETERS.len() as isize;
+        let i = PARAMETERS.iter().position(|&p| p == self).unwrap() as isize;
+        PARAMETERS[((i + steps) % len + len) as usize % PARAMETERS.len()]
+    }
+}
+
+impl Variables {
+    /// Every parameter and the field it lives in. The controls and the
+    /// properties both go through here.
+    fn fields(&mut self) -> Vec<(Parameter, &mut f32)> {
+        let Variables {
+            ref mut center,
+            ref mut separation,
+            ref mut matrix_size,
+            ref mut ray_proportion,
+            ref mut glow_amount,
+            ref mut cross_intensity,
+            ..
+        } = *self;
+        let mut center = center.coords.iter_mut();
+        let mut separation = separation.iter_mut();
+        let mut matrix_size = matrix_size.iter_mut();
+        vec![
+            (Parameter::CenterX, center.next().unwrap()),
+            (Parameter::CenterY, center.next().unwrap()),
+            (Parameter::SeparationX, separation.next().unwrap()),
+            (Parameter::SeparationY, separation.next().unwrap()),
+            (Parameter::MatrixWidth, matrix_size.next().unwrap()),
+            (Parameter::MatrixHeight, matrix_size.next().unwrap()),
+            (Parameter::RayProportion, ray_proportion),
+            (Parameter::GlowAmount, glow_amount),
+            (Parameter::CrossIntensity, cross_intensity),
+        ]
+    }
+    fn field(&mut self, parameter: Parameter) -> &mut f32 {
+        self.fields()
+            .into_iter()
+            .find(|&(p, _)| p == parameter)
+            .map(|(_, field)| field)
+            .unwrap()
+    }
+    fn get(&mut self, parameter: Parameter) -> f32 {
+        *self.field(parameter)
+    }
+    /// Sets `parameter`, kept within its range.
+    fn set(&mut self, parameter: Parameter, value: f32) {
+        let (low, high) = parameter.range();
+        let value = value.max(low).min(high);
+        *self.field(parameter) = match parameter {
+            // Whole cells only
+            Parameter::MatrixWidth | Parameter::MatrixHeight => value.round(),
+            _ => value,
+        };
+    }
+    /// Moves the selected parameter by `fraction` of its range.
+    fn nudge(&mut self, fraction: f32) {
+        let parameter = self.selected;
+        let (low, high) = parameter.range();
+        let step = (high - low) * fraction;
+        // Cells change one at a time at least
+        let step = match parameter {
+            Parameter::MatrixWidth | Parameter::MatrixHeight => step.signum() * step.abs().max(1.0),
+            _ => step,
+        };
+        let value = self.get(parameter) + step;
+        self.set(parameter, value);
+    }
+    /// Prints the selected parameter when it isn't the one printed last, so
+    /// holding a key or turning the wheel doesn't flood the terminal.
+    fn show_selected(&mut self) {
+        if self.shown != Some(self.selected) {
+            println!("{}: {}", self.selected.name(), self.get(self.selected));
+            self.shown = Some(self.selected);
+        }
+    }
+    fn preset(&self) -> Preset {
+        Preset {
+            center: [self.center.x, self.center.y],
+            separation: [self.separation.x, self.separation.y],
+            matrix_size: [self.matrix_size.x, self.matrix_size.y],
+            ray_proportion: self.ray_proportion,
+            glow_amount: self.glow_amount,
+            cross_intensity: self.cross_intensity,
+        }
+    }
+    fn apply(&mut self, preset: &Preset) {
+        let values = [
+            (Parameter::CenterX, preset.center[0]),
+            (Parameter::CenterY, preset.center[1]),
+            (Parameter::SeparationX, preset.separation[0]),
+            (Parameter::SeparationY, preset.separation[1]),
+            (Parameter::MatrixWidth, preset.matrix_size[0]),
+            (Parameter::MatrixHeight, preset.matrix_size[1]),
+            (Parameter::RayProportion, preset.ray_proportion),
+            (Parameter::GlowAmount, preset.glow_amount),
+            (Parameter::CrossIntensity, preset.cross_intensity),
+        ];
+        for &(parameter, value) in values.iter() {
+            self.set(parameter, value);
+        }
+    }
+    fn save_preset(&self) {
+        if let Err(e) = self.preset().save(&self.preset) {
+            eprintln!("can't save {}: {}", self.preset.display(), e);
+        }
+    }
+    fn load_preset(&mut self) {
+        match Preset::load(&self.preset) {
+            Ok(preset) => self.apply(&preset),
+            Err(e) => eprintln!("can't load {}: {}", self.preset.display(), e),
+        }
+    }
+}
+
+impl GetProperties for Variables {
+    fn properties<'a>(&'a mut self) -> Properties {
+        self.fields()
+            .into_iter()
+            .fold(Properties::new(), |properties, (parameter, field)| {
+                properties.add(parameter.name(), field)
+            })
+    }
+}
+
+/// Properties hand out the fields themselves, so whatever is written through
+/// them is brought back in range before the frame is drawn.
+struct KeepInRange;
+
+impl<B> Updater<B, Variables> for KeepInRange {
+    fn update(&mut self, _: &mut B, var: &mut Variables) {
+        for &parameter in PARAMETERS.iter() {
+            let value = var.get(parameter);
+            var.set(parameter, value);
+        }
+    }
+}
+
+/// Q and E go through the parameters, A and D change the selected one (J and
+/// K in finer steps) and F resets it. S saves them all to the preset file and
+/// W loads them back.
+impl OnKeyboard for Variables {
+    fn handle(&mut self, event: KeyboardEvent) {
+        if let KeyboardEvent::Pressed(key, _) = event {
+            match key {
+                Key::Q => self.selected = self.selected.cycle(-1),
+                Key::E => self.selected = self.selected.cycle(1),
+                Key::A => self.nudge(-0.02),
+                Key::D => self.nudge(0.02),
+                Key::J => self.nudge(-0.002),
+                Key::K => self.nudge(0.002),
+                Key::F => {
+                    let parameter = self.selected;
+                    self.set(parameter, Variables::default().get(parameter));
+                }
+                Key::S => self.save_preset(),
+                Key::W => self.load_preset(),
+            }
+            self.show_selected();
+        }
+    }
+}
+
+/// The keys from C2 (36) up pick a parameter, in the order of `PARAMETERS`,
+/// and the pitch bend wheel sweeps it through its range.
+impl OnMidiMessage for Variables {
+    fn on_midi_message(&mut self, msg: MidiMessage) {
+        match msg {
+            MidiMessage::NoteOn(key, _) if key >= 36 && ((key - 36) as usize) < PARAMETERS.len() => {
+                self.selected = PARAMETERS[(key - 36) as usize];
+            }
+            MidiMessage::PitchBendChange(amount) => {
+                let (low, high) = self.selected.range();
+                let value = low + (high - low) * amount as f32 / 16383.0;
+                let parameter = self.selected;
+                self.set(parameter, value);
+            }
+            _ => return,
+        }
+        self.show_selected();
+    }
+}
+
+/// The tunable parameters, as saved in a preset file. Missing ones take their
+/// default value.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Preset {
+    center: [f32; 2],
+    separation: [f32; 2],
+    matrix_size: [f32; 2],
+    ray_proportion: f32,
+    glow_amount: f32,
+    cross_intensity: f32,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Variables::default().preset()
+    }
+}
+
+impl Preset {
+    fn load<P: AsRef<Path>>(path: P) -> Result<Preset, PresetError> {
+        let mut source = String::new();
+        File::open(path)?.read_to_string(&mut source)?;
+        Ok(toml::from_str(&source)?)
+    }
+    fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), PresetError> {
+        let source = toml::to_string(self)?;
+        File::create(path)?.write_all(source.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum PresetError {
+    Io(io::Error),
+    Syntax(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PresetError::Io(ref e) => write!(f, "{}", e),
+            PresetError::Syntax(ref e) => write!(f, "invalid preset: {}", e),
+            PresetError::Serialize(ref e) => write!(f, "can't write the preset: {}", e),
+        }
+    }
+}
+
+impl Error for PresetError {}
+
+impl From<io::Error> for PresetError {
+    fn from(e: io::Error) -> Self {
+        PresetError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for PresetError {
+    fn from(e: toml::de::Error) -> Self {
+        PresetError::Syntax(e)
+    }
+}
+
+impl From<toml::ser::Error> for PresetError {
+    fn from(e: toml::ser::Error) -> Self {
+        PresetError::Serialize(e)
+    }
+}
+
+/// The weather sets the colour, and moves glow_amount and ray_proportion by
+/// as much as it changed since the last forecast (from the defaults at
+/// first), so what was tuned by hand is kept.
+impl OnWeather for Variables {
+    fn on_weather(&mut self, look: WeatherLook) {
+        self.current_color = look.current_color;
+        let (glow_amount, ray_proportion) = match self.weather {
+            Some(ref last) => (last.glow_amount, last.ray_proportion),
+            None => {
+                let default = Variables::default();
+                (default.glow_amount, default.ray_proportion)
+            }
+        };
+        let changes = [
+            (Parameter::GlowAmount, look.glow_amount - glow_amount),
+            (Parameter::RayProportion, look.ray_proportion - ray_proportion),
+        ];
+        for &(parameter, change) in changes.iter() {
+            let value = self.get(parameter) + change;
+            self.set(parameter, value);
+        }
+        self.weather = Some(look);
+    }
+}
+
 struct ColorRotator;
 
 impl<B> Updater<B, Variables> for ColorRotator {
@@ -67,70 +473,90 @@ impl<B> Updater<B, Variables> for ColorRotator {
     }
 }
 
-struct Visual {}
+struct Visual {
+    rng: StdRng,
+}
 
 impl Visual {
     pub fn new() -> Self {
-        Visual {}
+        Visual::seeded(rand::thread_rng().gen())
+    }
+    /// Draws the same ray lengths on every run.
+    pub fn seeded(seed: u64) -> Self {
+        Visual {
+            rng: seeded_rng(seed),
+        }
     }
 }
 
-fn ray(pos: Point2<f32>, rot: Rotation2<f32>, len: f32) -> Mesh {
-    // Transformaciones esteticas
-    let scale = 0.04;
-    let rot = {
-        let rpos = rot * pos;
-        Rotation3::rotation_between(
-            &Vector3::new(pos.x, pos.y, 0.0),
-            &Vector3::new(rpos.x, rpos.y, 0.0),
-        ).unwrap()
-    };
-    let pos = Point3::new(pos.x, pos.y, -600.0 + len.abs() * 3.0);
-    let len = len.sqrt();
-
-    let r = Vertex::from(pos).color(1.0, 0.0, 0.0, 0.8);
-    let g = Vertex::from(pos + rot * Vector3::new(2.0 * len, 0.0, 0.0) * scale)
-        .color(0.0, 1.0, 0.0, 0.8);
-    let b = Vertex::from(pos + rot * Vector3::new(4.0 * len, 0.0, 0.0) * scale)
-        .color(0.0, 0.0, 1.0, 0.8);
-    let v1 = Vertex::from(pos + rot * Vector3::new(-1.0 * len, 0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v2 = Vertex::from(pos + rot * Vector3::new(1.0 * len, 0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v3 = Vertex::from(pos + rot * Vector3::new(3.0 * len, 0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v4 = Vertex::from(pos + rot * Vector3::new(5.0 * len, 0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v5 = Vertex::from(pos + rot * Vector3::new(-1.0 * len, -0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v6 = Vertex::from(pos + rot * Vector3::new(1.0 * len, -0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v7 = Vertex::from(pos + rot * Vector3::new(3.0 * len, -0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
-    let v8 = Vertex::from(pos + rot * Vector3::new(5.0 * len, -0.4, 0.0) * scale)
-        .color(0.0, 0.0, 0.0, 0.0);
+/// Vertices along and across each ray, minus one.
+const RAY_STEPS: (usize, usize) = (12, 4);
+
+/// How much of the ray `p` cells off the centre shows, 1 on the axes.
+///
+/// `equations::cross_intensity` is scaled for much larger matrices and
+/// barely fades any ray in these.
+fn cross_weight(p: &Vector2<f32>, reach: f32) -> f32 {
+    (-(p.x * p.y / reach.powi(2)).powi(2)).exp()
+}
+
+/// A quad around the ray leaving `pos` towards `rot`, tessellated and coloured
+/// by sampling `equations` at its vertices. `len` stretches the ray and
+/// `weight` fades it.
+fn ray(pos: Point2<f32>, rot: Rotation2<f32>, len: f32, weight: f32, var: &Variables) -> Mesh {
+    // Width of a ray, the unit the equations work in
+    let unit = 0.032;
+    let proportion = var.ray_proportion * len.abs().sqrt();
+    let z = -600.0 + len.abs() * 3.0;
+    let (columns, rows) = RAY_STEPS;
+
+    let mut vertices = Vec::with_capacity((columns + 1) * (rows + 1));
+    for i in 0..(columns + 1) {
+        for j in 0..(rows + 1) {
+            // A bit past both ends and sides, where ray_intensity fades out
+            let x = -0.75 + 2.5 * i as f32 / columns as f32;
+            let y = -0.75 + 1.5 * j as f32 / rows as f32;
+            let p = pos + rot * Vector2::new(x * proportion, y) * unit;
+
+            let local = equations::transform(
+                &(p / unit),
+                &(var.center / unit),
+                &(pos / unit),
+                &rot.inverse(),
+                proportion,
+            );
+            let intensity = equations::ray_intensity(&local);
+            let alpha = 1.0 - equations::glow_amount(intensity, 1.0 / var.glow_amount);
+            let along = local.x.max(0.0).min(1.0);
+            vertices.push(Vertex::at(Point3::new(p.x, p.y, z)).color(
+                equations::red_intensity(along),
+                equations::green_intensity(along),
+                equations::blue_intensity(along),
+                0.8 * weight * alpha.max(0.0).min(1.0),
+            ));
+        }
+    }
+
+    let at = |i: usize, j: usize| vertices[i * (rows + 1) + j];
+    let mut triangles = Vec::with_capacity(columns * rows * 2);
+    for i in 0..columns {
+        for j in 0..rows {
+            triangles.push(Triangle::new(at(i, j), at(i + 1, j), at(i, j + 1)));
+            triangles.push(Triangle::new(at(i, j + 1), at(i + 1, j), at(i + 1, j + 1)));
+        }
+    }
 
     Mesh {
-        triangles: vec![
-            Triangle::new(r, v1, v2),
-            Triangle::new(r, v5, v1),
-            Triangle::new(r, v6, v5),
-            Triangle::new(v2, g, r),
-            Triangle::new(v6, r, g),
-            Triangle::new(g, v2, v3),
-            Triangle::new(g, v7, v6),
-            Triangle::new(v3, b, g),
-            Triangle::new(v7, g, b),
-            Triangle::new(b, v3, v4),
-            Triangle::new(b, v4, v8),
-            Triangle::new(b, v8, v7),
-        ],
+        triangles,
         transform: Transform3::identity(),
     }
 }
 
-impl Renderer<VulkanBackend, Variables> for Visual {
-    fn render(&mut self, backend: &mut VulkanBackend, var: &Variables) {
+impl<B> Renderer<B, Variables> for Visual
+where
+    B: MeshBackend,
+{
+    fn render(&mut self, backend: &mut B, var: &Variables) {
         let (w, h) = (20, 20);
         //let (w, h) = backend.screen_size();
         backend.set_uniforms(Uniforms {
@@ -152,9 +578,8 @@ impl Renderer<VulkanBackend, Variables> for Visual {
         use rand::distributions::normal::Normal;
         use rand::distributions::IndependentSample;
         let normal = Normal::new(1.0, 0.1);
-        let mut rng = rand::thread_rng();
 
-        let mut Q: Vec<(Point2<f32>, Rotation2<f32>)> = Vec::new();
+        let mut Q: Vec<(Point2<f32>, Rotation2<f32>, Vector2<f32>)> = Vec::new();
 
         for j in 0..(var.matrix_size.y as u32 * 2 + 1) {
             for i in 0..(var.matrix_size.x as u32 * 2 + 1) {
@@ -164,7 +589,7 @@ impl Renderer<VulkanBackend, Variables> for Visual {
                 let p = var.matrix_size - Vector2::new(i as f32, j as f32);
                 let q = var.center + p.component_mul(&var.separation);
                 let r = Rotation2::rotation_between(&Vector2::x(), &p);
-                Q.push((q, r));
+                Q.push((q, r, p));
             }
         }
 
@@ -174,10 +599,11 @@ impl Renderer<VulkanBackend, Variables> for Visual {
                 .unwrap()
         });
 
-        for (q, rot) in Q {
-            let (x, y) = (q.x, q.y);
-            let len = normal.ind_sample(&mut rng) as f32 / (q.coords.norm() * 10.0);
-            backend.queue_render(ray(q, rot, len));
+        for (q, rot, p) in Q {
+            let len = normal.ind_sample(&mut self.rng) as f32 / (q.coords.norm() * 10.0);
+            // Rays off the axes fade, drawing a cross
+            let weight = cross_weight(&p, var.cross_intensity);
+            backend.queue_render(ray(q, rot, len, weight, var));
         }
 
         //
@@ -186,66 +612,150 @@ impl Renderer<VulkanBackend, Variables> for Visual {
     }
 }
 
-mod equations {
+#[cfg(test)]
+mod test {
+    use super::{cross_weight, ray, ColorRotator, KeepInRange, Parameter, Preset, Variables,
+                Visual};
+    use drafts::golden::{Golden, Harness};
+    use drafts::weather_scene::{OnWeather, WeatherLook};
+    use mursten::Updater;
+    use mursten_blocks::geometry::Vertex;
+    use mursten_blocks::midi::{MidiMessage, OnMidiMessage};
     use nalgebra::*;
-    use std::f32::consts::{E, PI};
-    use std::f32::EPSILON;
+    use std::env;
+    use std::fs;
+    use toml;
 
-    pub fn transform(
-        point: &Point2<f32>,
-        center: &Point2<f32>,
-        pivot: &Point2<f32>,
-        rot: &Rotation2<f32>,
-        proportion: f32,
-    ) -> Point2<f32> {
-        let scale = Matrix3::new_nonuniform_scaling(&Vector2::new(1.0 / proportion, 1.0));
-        Point2::from_homogeneous(scale * (rot * (point - pivot.coords)).to_homogeneous()).unwrap()
-    }
-    pub fn ray_intensity(point: &Point2<f32>) -> f32 {
-        let (x, y) = (point.x, point.y);
-        E.powf(-4.0 * x.powi(4) + 8.0 * x.powi(3) - 4.0 * x.powi(2) - 100.0 * y.powi(6))
+    fn vertices(var: &Variables, weight: f32) -> Vec<Vertex> {
+        ray(Point2::new(0.3, 0.2), Rotation2::new(0.6), 1.0, weight, var)
+            .triangles
+            .iter()
+            .flat_map(|t| vec![t.v1, t.v2, t.v3])
+            .collect()
     }
-    pub fn cross_intensity(point: &Point2<f32>, intensity: f32) -> f32 {
-        E.powf(-(0.001 / intensity.powi(4)) * (point.x * point.y).powi(2))
+
+    fn opacity(vertices: &[Vertex]) -> f32 {
+        vertices.iter().map(|v| v.color.w).sum()
     }
-    pub fn red_intensity(scalar: f32) -> f32 {
-        (PI * scalar.min(0.5)).cos().powi(2)
+
+    #[test]
+    fn ray_shading() {
+        let var = Variables::default();
+        let ray = vertices(&var, 1.0);
+        assert_eq!(ray.len(), 12 * 4 * 2 * 3);
+        // Red where it starts, blue where it ends
+        let first = ray.first().unwrap().color;
+        let last = ray.last().unwrap().color;
+        assert!(first.x > 0.99 && first.z < 0.01);
+        assert!(last.z > 0.99 && last.x < 0.01);
+        assert!(ray.iter().all(|v| v.color.w <= 0.8));
+
+        let glowing = vertices(&Variables { glow_amount: 8.0, ..Variables::default() }, 1.0);
+        assert!(opacity(&glowing) > opacity(&ray));
+
+        let reach = |vertices: Vec<Vertex>| {
+            vertices
+                .iter()
+                .map(|v| (v.position - Point3::new(0.3, 0.2, v.position.z)).norm())
+                .fold(0.0, f32::max)
+        };
+        let long = vertices(&Variables { ray_proportion: 10.0, ..Variables::default() }, 1.0);
+        assert!(reach(long) > 2.0 * reach(ray));
+
+        assert_eq!(opacity(&vertices(&var, 0.0)), 0.0);
+
+        // By default rays on the diagonal fade well before those on an axis
+        let axis = cross_weight(&Vector2::new(0.0, 7.0), var.cross_intensity);
+        let diagonal = cross_weight(&Vector2::new(7.0, 7.0), var.cross_intensity);
+        assert_eq!(axis, 1.0);
+        assert!(diagonal < 0.2, "{}", diagonal);
+        assert!(opacity(&vertices(&var, diagonal)) < 0.2 * opacity(&vertices(&var, axis)));
     }
-    pub fn green_intensity(scalar: f32) -> f32 {
-        (PI * scalar).sin().powi(2)
+
+    #[test]
+    fn golden_frame() {
+        let mut variables = Variables::default();
+        let image = Harness::new(160, 160)
+            .add_updater(ColorRotator)
+            .add_renderer(Visual::seeded(7))
+            .render(&mut variables, 4);
+        Golden::new("visuals").assert_matches(&image);
     }
-    pub fn blue_intensity(scalar: f32) -> f32 {
-        (PI * scalar.max(0.5)).cos().powi(2)
+
+    #[test]
+    fn tuning_parameters() {
+        assert_eq!(Parameter::RayProportion.cycle(-1), Parameter::CenterY);
+        assert_eq!(Parameter::CenterY.cycle(1), Parameter::RayProportion);
+
+        let mut var = Variables::default();
+        var.set(Parameter::GlowAmount, 100.0);
+        assert_eq!(var.glow_amount, 12.0);
+        var.selected = Parameter::MatrixWidth;
+        var.nudge(-0.002);
+        assert_eq!(var.matrix_size, Vector2::new(14.0, 15.0));
+
+        // Writes through the properties skip set, and are clamped before drawing
+        *var.field(Parameter::CenterY) = -3.0;
+        *var.field(Parameter::MatrixHeight) = 7.4;
+        KeepInRange.update(&mut (), &mut var);
+        assert_eq!(var.center, Point2::new(0.0, -1.0));
+        assert_eq!(var.matrix_size, Vector2::new(14.0, 7.0));
+
+        // The second key picks glow_amount, the wheel sweeps its range
+        var.on_midi_message(MidiMessage::NoteOn(37, 100));
+        assert_eq!(var.selected, Parameter::GlowAmount);
+        assert_eq!(var.shown, Some(Parameter::GlowAmount));
+        var.on_midi_message(MidiMessage::PitchBendChange(0));
+        assert_eq!(var.glow_amount, 0.5);
+        var.on_midi_message(MidiMessage::PitchBendChange(16383));
+        assert_eq!(var.glow_amount, 12.0);
+        var.on_midi_message(MidiMessage::NoteOn(60, 100));
+        assert_eq!(var.selected, Parameter::GlowAmount);
+        assert_eq!(var.shown, Some(Parameter::GlowAmount));
     }
-    pub fn glow_amount(scalar: f32, intensity: f32) -> f32 {
-        E.powf(-scalar / intensity)
+
+    #[test]
+    fn weather_keeps_the_tuning() {
+        let look = |glow_amount, ray_proportion| WeatherLook {
+            current_color: Vector3::new(1.0, 0.0, 0.0),
+            glow_amount,
+            ray_proportion,
+            light_intensity: 0.5,
+        };
+        let mut var = Variables::default();
+        var.set(Parameter::GlowAmount, 7.0);
+        var.on_weather(look(8.0, 2.0));
+        assert_eq!(var.current_color, Vector3::new(1.0, 0.0, 0.0));
+        assert_eq!(var.glow_amount, 10.0);
+        assert_eq!(var.ray_proportion, 2.0);
+
+        // Only the change since the last forecast, within range
+        var.on_weather(look(2.0, 2.0));
+        assert_eq!(var.glow_amount, 4.0);
+        var.on_weather(look(12.0, 12.0));
+        assert_eq!(var.glow_amount, 12.0);
+        assert_eq!(var.ray_proportion, 12.0);
     }
 
     #[test]
-    fn test_cross_intensity() {
-        // Center glows at full intensity
-        let v = Point2::new(0.0, 0.0);
-        let i = 0.123123;
-        assert_eq!(cross_intensity(&v, i), 1.0);
-
-        // Also the axis
-        let v = Point2::new(0.0, 4621.0);
-        let i = 1.0123;
-        assert_eq!(cross_intensity(&v, i), 1.0);
-
-        // With intensity 1 at a distance ~7 the rays intensity ~halves
-        let v = Point2::new(5.0, 5.0);
-        let i = 1.0;
-        assert!(cross_intensity(&v, i) - 0.53526145 < EPSILON);
-
-        // With intensity 1 at a distance ~14 the rays intensity already unnoticeable
-        let v = Point2::new(10.0, 10.0);
-        let i = 1.0;
-        assert!(cross_intensity(&v, i) < 0.0001);
-
-        // You need to duplicate the intensity to compensate
-        let v = Point2::new(10.0, 10.0);
-        let i = 2.0;
-        assert!(cross_intensity(&v, i) - 0.53526145 < EPSILON);
+    fn presets() {
+        let mut var = Variables::default();
+        var.set(Parameter::CenterX, 0.25);
+        var.set(Parameter::CrossIntensity, 2.0);
+        let path = env::temp_dir().join(format!("visuals-preset-test-{}.toml", ::std::process::id()));
+        var.preset().save(&path).unwrap();
+
+        let mut loaded = Variables::default();
+        loaded.apply(&Preset::load(&path).unwrap());
+        assert_eq!(loaded.preset(), var.preset());
+        fs::remove_file(&path).unwrap();
+
+        // Hand written ones can leave things out, and can't go out of range
+        let preset: Preset = toml::from_str("ray_proportion = 8.0\nmatrix_size = [40.0, 3.0]").unwrap();
+        assert_eq!(preset.glow_amount, 5.0);
+        loaded.apply(&preset);
+        assert_eq!(loaded.ray_proportion, 8.0);
+        assert_eq!(loaded.matrix_size, Vector2::new(30.0, 3.0));
+        assert_eq!(loaded.center, Point2::origin());
     }
 }
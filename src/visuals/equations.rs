@@ -0,0 +1,61 @@
+use nalgebra::*;
+use std::f32::consts::{E, PI};
+use std::f32::EPSILON;
+
+pub fn transform(
+    point: &Point2<f32>,
+    center: &Point2<f32>,
+    pivot: &Point2<f32>,
+    rot: &Rotation2<f32>,
+    proportion: f32,
+) -> Point2<f32> {
+    let scale = Matrix3::new_nonuniform_scaling(&Vector2::new(1.0 / proportion, 1.0));
+    Point2::from_homogeneous(scale * (rot * (point - pivot.coords)).to_homogeneous()).unwrap()
+}
+pub fn ray_intensity(point: &Point2<f32>) -> f32 {
+    let (x, y) = (point.x, point.y);
+    E.powf(-4.0 * x.powi(4) + 8.0 * x.powi(3) - 4.0 * x.powi(2) - 100.0 * y.powi(6))
+}
+pub fn cross_intensity(point: &Point2<f32>, intensity: f32) -> f32 {
+    E.powf(-(0.001 / intensity.powi(4)) * (point.x * point.y).powi(2))
+}
+pub fn red_intensity(scalar: f32) -> f32 {
+    (PI * scalar.min(0.5)).cos().powi(2)
+}
+pub fn green_intensity(scalar: f32) -> f32 {
+    (PI * scalar).sin().powi(2)
+}
+pub fn blue_intensity(scalar: f32) -> f32 {
+    (PI * scalar.max(0.5)).cos().powi(2)
+}
+pub fn glow_amount(scalar: f32, intensity: f32) -> f32 {
+    E.powf(-scalar / intensity)
+}
+
+#[test]
+fn test_cross_intensity() {
+    // Center glows at full intensity
+    let v = Point2::new(0.0, 0.0);
+    let i = 0.123123;
+    assert_eq!(cross_intensity(&v, i), 1.0);
+
+    // Also the axis
+    let v = Point2::new(0.0, 4621.0);
+    let i = 1.0123;
+    assert_eq!(cross_intensity(&v, i), 1.0);
+
+    // With intensity 1 at a distance ~7 the rays intensity ~halves
+    let v = Point2::new(5.0, 5.0);
+    let i = 1.0;
+    assert!(cross_intensity(&v, i) - 0.53526145 < EPSILON);
+
+    // With intensity 1 at a distance ~14 the rays intensity already unnoticeable
+    let v = Point2::new(10.0, 10.0);
+    let i = 1.0;
+    assert!(cross_intensity(&v, i) < 0.0001);
+
+    // You need to duplicate the intensity to compensate
+    let v = Point2::new(10.0, 10.0);
+    let i = 2.0;
+    assert!(cross_intensity(&v, i) - 0.53526145 < EPSILON);
+}
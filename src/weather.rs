@@ -1,71 +1,951 @@
+use drafts::weather_cache::{Clock, SystemClock};
+use drafts::weather_format::{chart, first_days, table};
+use drafts::weather_history::Recorder;
+use drafts::weather_location::{CityTable, Location};
+use drafts::weather_providers::connect;
+use drafts::weather_units::{Speed, Temperature, Units};
 use reqwest;
 use reqwest::Url;
+use serde_json::{self, Value};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use toml;
 
 
-const API_URL: &str = "http://api.openweathermap.org/data/2.5/forecast";
+pub const DEFAULT_BASE_URL: &str = "http://api.openweathermap.org/data/2.5";
+pub const OPEN_METEO_BASE_URL: &str = "https://api.open-meteo.com/v1";
 
-enum City {
-    BuenosAires,
+/// Environment variables, which take precedence over the config file.
+const API_KEY_VAR: &str = "WEATHER_API_KEY";
+const BASE_URL_VAR: &str = "WEATHER_BASE_URL";
+const PROVIDER_VAR: &str = "WEATHER_PROVIDER";
+/// Where the config file is, `weather.toml` in the working directory if unset.
+const CONFIG_VAR: &str = "WEATHER_CONFIG";
+const CONFIG_FILE: &str = "weather.toml";
+/// The start of the OpenWeatherMap key this repository once shipped in
+/// `src/weather.api_key`. It is public in the history, so it's refused.
+const LEAKED_API_KEY: &str = "7a257fe3";
+
+/// Who answers forecast requests.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Provider {
+    OpenWeatherMap,
+    /// Free and keyless, but only knows coordinates.
+    OpenMeteo,
+    /// An OpenWeatherMap answer recorded in a file, to work offline.
+    File(PathBuf),
 }
 
-impl City {
-    pub fn country(&self) -> Country {
+impl Provider {
+    fn default_base_url(&self) -> &'static str {
         match *self {
-            City::BuenosAires => Country::Argentina,
+            Provider::OpenMeteo => OPEN_METEO_BASE_URL,
+            _ => DEFAULT_BASE_URL,
+        }
+    }
+    fn needs_api_key(&self) -> bool {
+        *self == Provider::OpenWeatherMap
+    }
+}
+
+/// `openweathermap`, `open-meteo` or `file:path/to/forecast.json`.
+impl FromStr for Provider {
+    type Err = WeatherConfigError;
+    fn from_str(s: &str) -> Result<Self, WeatherConfigError> {
+        match s.trim().to_lowercase().as_str() {
+            "openweathermap" | "owm" => Ok(Provider::OpenWeatherMap),
+            "open-meteo" | "openmeteo" => Ok(Provider::OpenMeteo),
+            _ if s.trim().starts_with("file:") => Ok(Provider::File(s.trim()[5..].into())),
+            _ => Err(WeatherConfigError::UnknownProvider(s.to_owned())),
+        }
+    }
+}
+
+/// Where and how to reach the weather API.
+///
+/// ```toml
+/// provider = "openweathermap"
+/// api_key = "0123456789abcdef"
+/// base_url = "http://api.openweathermap.org/data/2.5"
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeatherConfig {
+    pub provider: Provider,
+    /// Empty for the providers that don't need one.
+    pub api_key: String,
+    pub base_url: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ConfigFile {
+    provider: Option<String>,
+    api_key: Option<String>,
+    base_url: Option<String>,
+}
+
+impl WeatherConfig {
+    pub fn new(api_key: &str) -> Self {
+        WeatherConfig {
+            provider: Provider::OpenWeatherMap,
+            api_key: api_key.to_owned(),
+            base_url: DEFAULT_BASE_URL.to_owned(),
+        }
+    }
+    /// A config for `provider` at its usual URL, without an API key.
+    pub fn of(provider: Provider) -> Self {
+        WeatherConfig {
+            base_url: provider.default_base_url().to_owned(),
+            api_key: String::new(),
+            provider,
+        }
+    }
+    pub fn base_url(self, base_url: &str) -> Self {
+        WeatherConfig {
+            base_url: base_url.to_owned(),
+            ..self
         }
     }
-    pub fn to_api_param(&self) -> &'static str {
+    /// Reads the config file, if there is one, and then the environment.
+    pub fn load() -> Result<Self, WeatherConfigError> {
+        let path = env::var(CONFIG_VAR).unwrap_or_else(|_| CONFIG_FILE.to_owned());
+        let file = if Path::new(&path).exists() || env::var(CONFIG_VAR).is_ok() {
+            let mut source = String::new();
+            File::open(&path)?.read_to_string(&mut source)?;
+            Some(source)
+        } else {
+            None
+        };
+        WeatherConfig::from_sources(file.as_ref().map(|s| s.as_str()), |name| {
+            env::var(name).ok()
+        })
+    }
+    fn from_sources<F>(file: Option<&str>, var: F) -> Result<Self, WeatherConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let file: ConfigFile = match file {
+            Some(source) => toml::from_str(source)?,
+            None => ConfigFile::default(),
+        };
+        let provider: Provider = match var(PROVIDER_VAR).or(file.provider) {
+            Some(provider) => provider.parse()?,
+            None => Provider::OpenWeatherMap,
+        };
+        let api_key = var(API_KEY_VAR)
+            .or(file.api_key)
+            .map(|key| key.trim().to_owned())
+            .unwrap_or_default();
+        if api_key.is_empty() && provider.needs_api_key() {
+            return Err(WeatherConfigError::MissingApiKey);
+        }
+        if api_key.len() == 32 && api_key.starts_with(LEAKED_API_KEY) {
+            return Err(WeatherConfigError::LeakedApiKey);
+        }
+        let base_url = var(BASE_URL_VAR)
+            .or(file.base_url)
+            .unwrap_or_else(|| provider.default_base_url().to_owned());
+        Url::parse(&base_url).map_err(|_| WeatherConfigError::BadBaseUrl(base_url.clone()))?;
+        Ok(WeatherConfig {
+            provider,
+            api_key,
+            base_url,
+        })
+    }
+    pub fn endpoint(&self, name: &str) -> String {
+        format!("{}/{}", self.base_url.trim_right_matches('/'), name)
+    }
+}
+
+#[derive(Debug)]
+pub enum WeatherConfigError {
+    MissingApiKey,
+    LeakedApiKey,
+    UnknownProvider(String),
+    BadBaseUrl(String),
+    Io(io::Error),
+    Syntax(toml::de::Error),
+}
+
+impl fmt::Display for WeatherConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            City::BuenosAires => "Buenos Aires,ar",
+            WeatherConfigError::MissingApiKey => write!(
+                f,
+                "no weather API key: set {} or add api_key to {} (or the file named by {})",
+                API_KEY_VAR, CONFIG_FILE, CONFIG_VAR
+            ),
+            WeatherConfigError::LeakedApiKey => write!(
+                f,
+                "the weather API key is the one that leaked in this repository's history, \
+                 it has to be revoked: get a new one and set it in {} or {}",
+                API_KEY_VAR, CONFIG_FILE
+            ),
+            WeatherConfigError::UnknownProvider(ref provider) => write!(
+                f,
+                "unknown weather provider \"{}\", try openweathermap, open-meteo or file:PATH",
+                provider
+            ),
+            WeatherConfigError::BadBaseUrl(ref url) => write!(f, "invalid base URL \"{}\"", url),
+            WeatherConfigError::Io(ref e) => write!(f, "can't read the weather config: {}", e),
+            WeatherConfigError::Syntax(ref e) => write!(f, "invalid weather config: {}", e),
         }
     }
 }
 
-enum Country {
-    Argentina,
+impl Error for WeatherConfigError {}
+
+impl From<io::Error> for WeatherConfigError {
+    fn from(e: io::Error) -> Self {
+        WeatherConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for WeatherConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        WeatherConfigError::Syntax(e)
+    }
 }
 
-struct WeatherRequest {
-    city: City,
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeatherRequest {
+    location: Location,
+    units: Units,
 }
 
 impl WeatherRequest {
-    pub fn of(city: City) -> Self {
+    pub fn of<L: Into<Location>>(place: L) -> Self {
         WeatherRequest {
-            city,
+            location: place.into(),
+            units: Units::default(),
         }
     }
+    pub fn units(self, units: Units) -> Self {
+        WeatherRequest { units, ..self }
+    }
+    pub fn location(&self) -> &Location {
+        &self.location
+    }
+    pub fn requested_units(&self) -> Units {
+        self.units
+    }
+    /// Identifies the answer to this request, for caching.
+    pub fn key(&self) -> String {
+        let mut params = self.location.to_api_params();
+        params.push(("units", self.units.to_api_param().to_owned()));
+        params
+            .iter()
+            .map(|&(name, ref value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&")
+    }
 }
 
-impl WeatherRequest {
-    fn into_url(self) -> String {
-        format!("{}?q={}&APPID={}",
-                API_URL,
-                self.city.to_api_param(),
-                include_str!("weather.api_key"))
+/// A 5 day forecast in 3 hour steps, as returned by `/forecast`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Forecast {
+    pub city: ForecastCity,
+    /// The units the API answered in.
+    pub units: Units,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ForecastCity {
+    pub id: u64,
+    pub name: String,
+    pub country: String,
+    pub coord: Coordinates,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// The weather expected for the 3 hours starting at `time`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds since the Unix epoch, UTC.
+    pub time: u64,
+    pub main: Measurements,
+    pub weather: Vec<Condition>,
+    pub clouds: Clouds,
+    pub wind: Wind,
+    pub rain: Volume,
+    pub snow: Volume,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Measurements {
+    pub temp: Temperature,
+    pub temp_min: Temperature,
+    pub temp_max: Temperature,
+    /// hPa
+    pub pressure: f64,
+    /// Percentage
+    pub humidity: f64,
+}
+
+/// One of the weather condition codes, like 500 for light rain.
+/// See https://openweathermap.org/weather-conditions
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Condition {
+    pub id: u16,
+    pub main: String,
+    pub description: String,
+    pub icon: String,
+}
+
+/// Cloud cover percentage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Clouds {
+    pub all: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Wind {
+    pub speed: Speed,
+    /// Meteorological degrees, where the wind comes from.
+    pub deg: f64,
+}
+
+/// Millimetres of rain or snow fallen in the 3 hours.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Volume {
+    #[serde(rename = "3h", default)]
+    pub last_3h: f64,
+}
+
+/// The `/forecast` JSON as is. Numbers carry no units, so they are only
+/// typed once the units they were asked in are known.
+#[derive(Deserialize)]
+struct RawForecast {
+    city: ForecastCity,
+    list: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+struct RawEntry {
+    dt: u64,
+    main: RawMeasurements,
+    #[serde(default)]
+    weather: Vec<Condition>,
+    #[serde(default)]
+    clouds: Clouds,
+    wind: RawWind,
+    #[serde(default)]
+    rain: Volume,
+    #[serde(default)]
+    snow: Volume,
+}
+
+#[derive(Deserialize)]
+struct RawMeasurements {
+    temp: f64,
+    temp_min: f64,
+    temp_max: f64,
+    pressure: f64,
+    humidity: f64,
+}
+
+#[derive(Deserialize)]
+struct RawWind {
+    speed: f64,
+    #[serde(default)]
+    deg: f64,
+}
+
+impl RawForecast {
+    fn into_forecast(self, units: Units) -> Forecast {
+        Forecast {
+            city: self.city,
+            units,
+            entries: self.list
+                .into_iter()
+                .map(|entry| Entry {
+                    time: entry.dt,
+                    main: Measurements {
+                        temp: units.temperature(entry.main.temp),
+                        temp_min: units.temperature(entry.main.temp_min),
+                        temp_max: units.temperature(entry.main.temp_max),
+                        pressure: entry.main.pressure,
+                        humidity: entry.main.humidity,
+                    },
+                    weather: entry.weather,
+                    clouds: entry.clouds,
+                    wind: Wind {
+                        speed: units.speed(entry.wind.speed),
+                        deg: entry.wind.deg,
+                    },
+                    rain: entry.rain,
+                    snow: entry.snow,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Forecast {
+    /// Parses a `/forecast` response body asked in `units`, turning error
+    /// payloads like `{"cod":"401","message":"Invalid API key"}` into
+    /// `WeatherError::Api`.
+    pub fn from_json(body: &str, units: Units) -> Result<Self, WeatherError> {
+        let value: Value = serde_json::from_str(body)?;
+        let code = match value.get("cod") {
+            Some(&Value::String(ref code)) => code.parse().ok(),
+            Some(&Value::Number(ref code)) => code.as_u64().map(|code| code as u16),
+            _ => None,
+        };
+        match code {
+            Some(200) | None => {
+                let raw: RawForecast = serde_json::from_value(value)?;
+                Ok(raw.into_forecast(units))
+            }
+            Some(code) => Err(WeatherError::Api {
+                code,
+                message: value
+                    .get("message")
+                    .and_then(|m| m.as_str())
+                    .unwrap_or("")
+                    .to_owned(),
+            }),
+        }
+    }
+}
+
+impl Forecast {
+    /// The same forecast with every measure converted to `units`.
+    pub fn in_units(self, units: Units) -> Self {
+        Forecast {
+            units,
+            entries: self.entries
+                .into_iter()
+                .map(|entry| Entry {
+                    main: Measurements {
+                        temp: entry.main.temp.in_units(units),
+                        temp_min: entry.main.temp_min.in_units(units),
+                        temp_max: entry.main.temp_max.in_units(units),
+                        ..entry.main
+                    },
+                    wind: Wind {
+                        speed: entry.wind.speed.in_units(units),
+                        ..entry.wind
+                    },
+                    ..entry
+                })
+                .collect(),
+            ..self
+        }
     }
 }
 
-trait WeatherRequestClient {
-    fn get_forecast(&self, wr: WeatherRequest) -> reqwest::Result<reqwest::Response>;
+impl Entry {
+    /// The most significant condition, as OpenWeatherMap lists it first.
+    pub fn condition(&self) -> Option<&Condition> {
+        self.weather.first()
+    }
+}
+
+#[derive(Debug)]
+pub enum WeatherError {
+    Http(reqwest::Error),
+    /// The API answered with an error code, like 401 for a bad key.
+    Api { code: u16, message: String },
+    Parse(serde_json::Error),
+    /// Not sent to stay under the API's calls per minute.
+    RateLimited { retry_in: u64 },
+    /// The provider can't tell where the location is.
+    UnknownPlace(String),
+    Io(io::Error),
+}
+
+impl WeatherError {
+    /// Whether asking again later may work, as opposed to the request being
+    /// wrong.
+    pub fn is_unavailable(&self) -> bool {
+        match *self {
+            WeatherError::Http(_) | WeatherError::RateLimited { .. } => true,
+            WeatherError::Api { code, .. } => code == 429 || code >= 500,
+            WeatherError::Parse(_) | WeatherError::UnknownPlace(_) | WeatherError::Io(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for WeatherError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WeatherError::Http(ref e) => write!(f, "request failed: {}", e),
+            WeatherError::Api { code, ref message } => {
+                write!(f, "the weather API answered {}: {}", code, message)
+            }
+            WeatherError::Parse(ref e) => write!(f, "unexpected forecast format: {}", e),
+            WeatherError::RateLimited { retry_in } => {
+                write!(f, "too many requests, try again in {} seconds", retry_in)
+            }
+            WeatherError::UnknownPlace(ref place) => write!(f, "can't find {}", place),
+            WeatherError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for WeatherError {}
+
+impl From<reqwest::Error> for WeatherError {
+    fn from(e: reqwest::Error) -> Self {
+        WeatherError::Http(e)
+    }
+}
+
+impl From<io::Error> for WeatherError {
+    fn from(e: io::Error) -> Self {
+        WeatherError::Io(e)
+    }
 }
 
-impl WeatherRequestClient for reqwest::Client {
-    fn get_forecast(&self, wr: WeatherRequest) -> reqwest::Result<reqwest::Response> {
-        //format!("{}?q={}&APPID={}",
-        self.get(API_URL)
-            .query(&[("q", wr.city.to_api_param()),
-                     ("APPID", include_str!("weather.api_key")),
-                     ("unit", "metric")])
-            .send()
+impl From<serde_json::Error> for WeatherError {
+    fn from(e: serde_json::Error) -> Self {
+        WeatherError::Parse(e)
     }
 }
 
+pub trait WeatherRequestClient {
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError>;
+    /// Who answers, so answers from different providers or servers can be
+    /// told apart.
+    fn source(&self) -> String {
+        String::new()
+    }
+}
+
+impl<C> WeatherRequestClient for Box<C>
+where
+    C: WeatherRequestClient + ?Sized,
+{
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+        (**self).get_forecast(wr)
+    }
+    fn source(&self) -> String {
+        (**self).source()
+    }
+}
+
+/// The OpenWeatherMap API, or anything answering like it at the configured URL.
+pub struct OpenWeatherMap {
+    http: reqwest::Client,
+    config: WeatherConfig,
+}
+
+impl OpenWeatherMap {
+    pub fn new(config: WeatherConfig) -> Self {
+        OpenWeatherMap {
+            http: reqwest::Client::new(),
+            config,
+        }
+    }
+}
+
+impl WeatherRequestClient for OpenWeatherMap {
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+        let mut params = wr.location.to_api_params();
+        params.push(("APPID", self.config.api_key.clone()));
+        params.push(("units", wr.units.to_api_param().to_owned()));
+        let body = self.http.get(&self.config.endpoint("forecast"))
+            .query(&params)
+            .send()?
+            .text()?;
+        Forecast::from_json(&body, wr.units)
+    }
+    fn source(&self) -> String {
+        format!("openweathermap {}", self.config.base_url)
+    }
+}
+
+
+const USAGE: &str = "usage:
+    weather [--city PLACE] [--units metric|imperial|standard] [--days N]
+            [--format table|json|chart] [--record CSV]
+
+PLACE is written \"Buenos Aires,ar\", as a city id, \"lat,lon\" or \"zip:CODE,cc\",
+and is Buenos Aires when not given. Up to 5 days are forecast, in 3 hour slots.
+With --record, the whole forecast is also appended to a CSV history.
+
+Exit codes: 1 for other errors, 2 for bad usage, 3 when the API key is missing
+or refused, 4 when the weather API can't be reached, 5 for unknown places.";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Table,
+    Json,
+    Chart,
+}
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    request: WeatherRequest,
+    days: u64,
+    format: Format,
+    record: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, CliError> {
+        let mut place = "Buenos Aires,ar".to_owned();
+        let mut units = Units::default();
+        let mut days = 5;
+        let mut format = Format::Table;
+        let mut record = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--city" | "-c" => place = args.next().ok_or(CliError::Usage)?.clone(),
+                "--units" | "-u" => units = parse_value(args.next())?,
+                "--days" | "-d" => days = parse_value(args.next())?,
+                "--format" | "-f" => {
+                    format = match args.next().map(|f| f.as_str()) {
+                        Some("table") => Format::Table,
+                        Some("json") => Format::Json,
+                        Some("chart") => Format::Chart,
+                        _ => return Err(CliError::Usage),
+                    }
+                }
+                "--record" => record = Some(args.next().ok_or(CliError::Usage)?.into()),
+                _ => return Err(CliError::Usage),
+            }
+        }
+        if days == 0 {
+            return Err(CliError::Usage);
+        }
+        let location = Location::from(place.as_str()).resolve(&CityTable::bundled());
+        Ok(Options {
+            request: WeatherRequest::of(location).units(units),
+            days,
+            format,
+            record,
+        })
+    }
+}
+
+fn parse_value<T: FromStr>(arg: Option<&String>) -> Result<T, CliError> {
+    arg.and_then(|a| a.parse().ok()).ok_or(CliError::Usage)
+}
 
 pub fn main() {
-    let client = reqwest::Client::new();
-    let request = WeatherRequest::of(City::BuenosAires);
-    println!("Making request of: {}", request.city.to_api_param());
-    let response = client.get_forecast(request).unwrap().text().unwrap();
-    println!("Response: {}", response);
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(output) => print!("{}", output),
+        Err(CliError::Usage) => {
+            eprintln!("{}", USAGE);
+            process::exit(CliError::Usage.exit_code());
+        }
+        Err(e) => {
+            eprintln!("weather: {}", e);
+            process::exit(e.exit_code());
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<String, CliError> {
+    let options = Options::parse(args)?;
+    let client = connect(WeatherConfig::load()?);
+    let forecast = client.get_forecast(options.request)?;
+    if let Some(path) = options.record {
+        Recorder::new(path).record(&forecast, SystemClock.now())?;
+    }
+    let forecast = first_days(&forecast, options.days);
+    Ok(match options.format {
+        Format::Table => table(&forecast),
+        Format::Json => serde_json::to_string_pretty(&forecast).map_err(WeatherError::from)? + "\n",
+        Format::Chart => chart(&forecast, 12),
+    })
+}
+
+#[derive(Debug)]
+enum CliError {
+    Usage,
+    Config(WeatherConfigError),
+    Weather(WeatherError),
+}
+
+impl CliError {
+    fn exit_code(&self) -> i32 {
+        match *self {
+            CliError::Usage => 2,
+            CliError::Config(WeatherConfigError::MissingApiKey)
+            | CliError::Config(WeatherConfigError::LeakedApiKey) => 3,
+            CliError::Config(_) => 1,
+            CliError::Weather(WeatherError::Api { code: 401, .. })
+            | CliError::Weather(WeatherError::Api { code: 403, .. }) => 3,
+            CliError::Weather(WeatherError::Api { code: 404, .. })
+            | CliError::Weather(WeatherError::UnknownPlace(_)) => 5,
+            CliError::Weather(ref e) if e.is_unavailable() => 4,
+            CliError::Weather(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CliError::Usage => write!(f, "{}", USAGE),
+            CliError::Config(ref e) => write!(f, "{}", e),
+            CliError::Weather(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<WeatherConfigError> for CliError {
+    fn from(e: WeatherConfigError) -> Self {
+        CliError::Config(e)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(e: io::Error) -> Self {
+        CliError::Weather(WeatherError::Io(e))
+    }
+}
+
+impl From<WeatherError> for CliError {
+    fn from(e: WeatherError) -> Self {
+        CliError::Weather(e)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::{CliError, Forecast, Format, OpenWeatherMap, Options, Provider,
+                          WeatherConfig, WeatherConfigError, WeatherError, WeatherRequest,
+                          WeatherRequestClient, DEFAULT_BASE_URL, LEAKED_API_KEY};
+    use drafts::weather_location::Location;
+    use drafts::weather_units::{Speed, Temperature, Units};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers a single HTTP request with `body` and returns the request line.
+    fn serve_once(body: &'static str) -> (String, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut header = String::new();
+            while reader.read_line(&mut header).unwrap() > 2 {
+                header.clear();
+            }
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            ).unwrap();
+            request_line
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn config_sources() {
+        let no_vars = |_: &str| None;
+        match WeatherConfig::from_sources(None, no_vars) {
+            Err(WeatherConfigError::MissingApiKey) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match WeatherConfig::from_sources(Some("api_key = \"  \""), no_vars) {
+            Err(WeatherConfigError::MissingApiKey) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let leaked = format!("{}{}", LEAKED_API_KEY, "0".repeat(24));
+        match WeatherConfig::from_sources(None, |name| match name {
+            "WEATHER_API_KEY" => Some(leaked.clone()),
+            _ => None,
+        }) {
+            Err(WeatherConfigError::LeakedApiKey) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let file = "api_key = \"from-file\"\nbase_url = \"http://localhost:8080/\"";
+        let config = WeatherConfig::from_sources(Some(file), no_vars).unwrap();
+        assert_eq!(config.api_key, "from-file");
+        assert_eq!(config.endpoint("forecast"), "http://localhost:8080/forecast");
+
+        let config = WeatherConfig::from_sources(Some(file), |name| match name {
+            "WEATHER_API_KEY" => Some("from-env".to_owned()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config, WeatherConfig::new("from-env").base_url("http://localhost:8080/"));
+
+        let config = WeatherConfig::from_sources(None, |name| match name {
+            "WEATHER_API_KEY" => Some("from-env".to_owned()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.base_url, DEFAULT_BASE_URL);
+
+        // Only OpenWeatherMap needs a key
+        let config = WeatherConfig::from_sources(Some("provider = \"open-meteo\""), no_vars).unwrap();
+        assert_eq!(config, WeatherConfig::of(Provider::OpenMeteo));
+        let config = WeatherConfig::from_sources(None, |name| match name {
+            "WEATHER_PROVIDER" => Some("file:fixtures/forecast.json".to_owned()),
+            _ => None,
+        }).unwrap();
+        assert_eq!(config.provider, Provider::File("fixtures/forecast.json".into()));
+        match WeatherConfig::from_sources(Some("provider = \"bbc\""), no_vars) {
+            Err(WeatherConfigError::UnknownProvider(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        match WeatherConfig::from_sources(Some("api_key = 1"), no_vars) {
+            Err(WeatherConfigError::Syntax(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match WeatherConfig::from_sources(Some("api_key = \"k\"\nbase_url = \"nope\""), no_vars) {
+            Err(WeatherConfigError::BadBaseUrl(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn request_goes_to_the_configured_server() {
+        let (url, server) = serve_once(include_str!("fixtures/owm_forecast.json"));
+        let client = OpenWeatherMap::new(WeatherConfig::new("secret").base_url(&url));
+        let forecast = client
+            .get_forecast(WeatherRequest::of("Buenos Aires,ar").units(Units::Imperial))
+            .unwrap();
+        assert_eq!(forecast.entries.len(), 3);
+        assert_eq!(forecast.units, Units::Imperial);
+        let request_line = server.join().unwrap();
+        assert!(request_line.starts_with("GET /forecast?q=Buenos"), "{}", request_line);
+        assert!(request_line.contains("APPID=secret"), "{}", request_line);
+        assert!(request_line.contains("units=imperial"), "{}", request_line);
+    }
+
+    #[test]
+    fn parse_forecast() {
+        let forecast = Forecast::from_json(include_str!("fixtures/owm_forecast.json"), Units::Standard)
+            .unwrap();
+        assert_eq!(forecast.city.id, 3435910);
+        assert_eq!(forecast.city.name, "Buenos Aires");
+        assert_eq!(forecast.city.country, "AR");
+        assert_eq!(forecast.entries.len(), 3);
+
+        let clear = &forecast.entries[0];
+        assert_eq!(clear.time, 1540036800);
+        assert_eq!(clear.main.temp, Temperature::Kelvin(291.15));
+        assert_eq!(clear.main.humidity, 72.0);
+        assert_eq!(clear.wind.speed, Speed::MetresPerSecond(4.6));
+        assert_eq!(clear.condition().unwrap().id, 800);
+        assert_eq!(clear.rain.last_3h, 0.0);
+
+        let rainy = &forecast.entries[1];
+        assert_eq!(rainy.clouds.all, 88.0);
+        assert_eq!(rainy.rain.last_3h, 1.25);
+        assert_eq!(rainy.snow.last_3h, 0.0);
+
+        let snowy = &forecast.entries[2];
+        assert_eq!(snowy.weather.len(), 2);
+        assert_eq!(snowy.condition().unwrap().main, "Snow");
+        assert_eq!(snowy.rain.last_3h, 0.0);
+        assert_eq!(snowy.snow.last_3h, 0.3);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let errors = [
+            (include_str!("fixtures/owm_unauthorized.json"), 401),
+            (include_str!("fixtures/owm_not_found.json"), 404),
+            ("{\"cod\":\"401\"}", 401),
+            ("{\"cod\":429,\"message\":\"too many requests\"}", 429),
+        ];
+        for &(body, expected) in errors.iter() {
+            match Forecast::from_json(body, Units::Metric) {
+                Err(WeatherError::Api { code, .. }) => assert_eq!(code, expected),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        match Forecast::from_json("{\"cod\":\"200\",\"list\":[]}", Units::Metric) {
+            Err(WeatherError::Parse(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match Forecast::from_json("<html>", Units::Metric) {
+            Err(WeatherError::Parse(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_each_unit_system() {
+        let fixtures = [
+            (include_str!("fixtures/owm_forecast.json"), Units::Standard),
+            (include_str!("fixtures/owm_forecast_metric.json"), Units::Metric),
+            (include_str!("fixtures/owm_forecast_imperial.json"), Units::Imperial),
+        ];
+        for &(body, units) in fixtures.iter() {
+            let forecast = Forecast::from_json(body, units).unwrap();
+            let entry = &forecast.entries[0];
+            assert_eq!(entry.main.temp, entry.main.temp.in_units(units));
+            assert!((entry.main.temp.celsius() - 18.0).abs() < 0.01, "{:?}", units);
+            assert!((entry.main.temp_min.celsius() - 17.25).abs() < 0.01, "{:?}", units);
+            assert!((entry.wind.speed.metres_per_second() - 4.6).abs() < 0.01, "{:?}", units);
+            assert_eq!(entry.wind.speed, entry.wind.speed.in_units(units));
+        }
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|a| a.replace('_', " ")).collect()
+    }
+
+    #[test]
+    fn command_line() {
+        let options = Options::parse(&args("--city Rosario,ar --units imperial -d 2 -f chart"))
+            .unwrap();
+        assert_eq!(options.days, 2);
+        assert_eq!(options.format, Format::Chart);
+        assert_eq!(options.request.requested_units(), Units::Imperial);
+        assert_eq!(options.record, None);
+        let recording = Options::parse(&args("--record history.csv")).unwrap();
+        assert_eq!(recording.record, Some("history.csv".into()));
+
+        let defaults = Options::parse(&[]).unwrap();
+        assert_eq!(defaults, Options::parse(&args("--city Buenos_Aires,ar")).unwrap());
+        assert_eq!(defaults.request.requested_units(), Units::Metric);
+        assert_eq!(defaults.format, Format::Table);
+        assert_eq!(
+            Options::parse(&args("-c -34.6,-58.4")).unwrap().request.location(),
+            &Location::at(-34.6, -58.4)
+        );
+
+        for bad in &["--days 0", "--days", "--units furlongs", "--format pie", "Rosario"] {
+            match Options::parse(&args(bad)) {
+                Err(CliError::Usage) => (),
+                other => panic!("unexpected result for {}: {:?}", bad, other),
+            }
+        }
+    }
+
+    #[test]
+    fn exit_codes() {
+        let api = |code| CliError::Weather(WeatherError::Api {
+            code,
+            message: String::new(),
+        });
+        assert_eq!(CliError::Usage.exit_code(), 2);
+        assert_eq!(CliError::Config(WeatherConfigError::MissingApiKey).exit_code(), 3);
+        assert_eq!(api(401).exit_code(), 3);
+        assert_eq!(api(503).exit_code(), 4);
+        assert_eq!(CliError::Weather(WeatherError::RateLimited { retry_in: 5 }).exit_code(), 4);
+        assert_eq!(api(404).exit_code(), 5);
+        let unknown = WeatherError::UnknownPlace("Atlantis".to_owned());
+        assert_eq!(CliError::Weather(unknown).exit_code(), 5);
+        assert_eq!(api(400).exit_code(), 1);
+    }
 }
@@ -0,0 +1,415 @@
+use drafts::weather::{Forecast, WeatherError, WeatherRequest, WeatherRequestClient};
+use serde_json;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The OpenWeatherMap free tier allows 60 calls per minute.
+pub const FREE_TIER_CALLS_PER_MINUTE: usize = 60;
+
+/// Seconds since the Unix epoch, so tests can decide when now is.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// A forecast and when it was fetched.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Cached {
+    pub fetched_at: u64,
+    pub forecast: Forecast,
+    /// Older than the TTL, served because a fresh one couldn't be fetched.
+    #[serde(skip)]
+    pub stale: bool,
+}
+
+struct State {
+    entries: HashMap<String, Cached>,
+    calls: VecDeque<u64>,
+}
+
+/// Answers from memory or disk while the last answer to the same request is
+/// younger than the TTL, and only asks `client` otherwise.
+///
+/// When asking fails because the API is unreachable, or would go over the
+/// calls per minute allowed, the last answer is served however old it is.
+pub struct CachingClient<C, K = SystemClock> {
+    client: C,
+    clock: K,
+    ttl: u64,
+    calls_per_minute: usize,
+    dir: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+impl<C> CachingClient<C, SystemClock>
+where
+    C: WeatherRequestClient,
+{
+    pub fn new(client: C) -> Self {
+        CachingClient {
+            client,
+            clock: SystemClock,
+            ttl: 10 * 60,
+            calls_per_minute: FREE_TIER_CALLS_PER_MINUTE,
+            dir: None,
+            state: Mutex::new(State {
+                entries: HashMap::new(),
+                calls: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl<C, K> CachingClient<C, K>
+where
+    C: WeatherRequestClient,
+    K: Clock,
+{
+    pub fn clock<L: Clock>(self, clock: L) -> CachingClient<C, L> {
+        CachingClient {
+            client: self.client,
+            clock,
+            ttl: self.ttl,
+            calls_per_minute: self.calls_per_minute,
+            dir: self.dir,
+            state: self.state,
+        }
+    }
+    pub fn ttl(self, ttl: Duration) -> Self {
+        CachingClient {
+            ttl: ttl.as_secs(),
+            ..self
+        }
+    }
+    pub fn calls_per_minute(self, calls_per_minute: usize) -> Self {
+        CachingClient {
+            calls_per_minute: calls_per_minute.max(1),
+            ..self
+        }
+    }
+    /// Keeps a JSON file per request in `dir`, so answers outlive the process.
+    pub fn cache_dir<P: Into<PathBuf>>(self, dir: P) -> Self {
+        CachingClient {
+            dir: Some(dir.into()),
+            ..self
+        }
+    }
+    /// Like `get_forecast`, also telling how old the forecast is.
+    ///
+    /// The lock is only held to look at and update the cache, not while
+    /// asking `client`, so a slow request doesn't hold up the others.
+    pub fn get_cached(&self, wr: WeatherRequest) -> Result<Cached, WeatherError> {
+        let key = format!("{} {}", self.client.source(), wr.key());
+        let now = self.clock.now();
+
+        let (cached, allowed) = {
+            let mut state = self.state.lock().unwrap();
+            let cached = match state.entries.get(&key) {
+                Some(cached) => Some(cached.clone()),
+                None => self.read(&key),
+            };
+            if let Some(ref cached) = cached {
+                if now < cached.fetched_at + self.ttl {
+                    state.entries.insert(key, cached.clone());
+                    return Ok(cached.clone());
+                }
+            }
+            while state.calls.front().map_or(false, |&t| t + 60 <= now) {
+                state.calls.pop_front();
+            }
+            let allowed = if state.calls.len() >= self.calls_per_minute {
+                Err(WeatherError::RateLimited {
+                    retry_in: state.calls[0] + 60 - now,
+                })
+            } else {
+                state.calls.push_back(now);
+                Ok(())
+            };
+            (cached, allowed)
+        };
+
+        match allowed.and_then(|_| self.client.get_forecast(wr)) {
+            Ok(forecast) => {
+                let fresh = Cached {
+                    fetched_at: now,
+                    forecast,
+                    stale: false,
+                };
+                self.write(&key, &fresh);
+                self.state.lock().unwrap().entries.insert(key, fresh.clone());
+                Ok(fresh)
+            }
+            Err(e) => match cached {
+                Some(cached) if e.is_unavailable() => Ok(Cached {
+                    stale: true,
+                    ..cached
+                }),
+                _ => Err(e),
+            },
+        }
+    }
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let name: String = key.chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect();
+        self.dir.as_ref().map(|dir| dir.join(name + ".json"))
+    }
+    fn read(&self, key: &str) -> Option<Cached> {
+        let file = File::open(self.path(key)?).ok()?;
+        serde_json::from_reader(BufReader::new(file)).ok()
+    }
+    /// The cache is only an optimisation, so failing to write it isn't an error.
+    fn write(&self, key: &str, cached: &Cached) {
+        if let Some(path) = self.path(key) {
+            let written = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| {
+                let file = BufWriter::new(File::create(&path)?);
+                serde_json::to_writer(file, cached).map_err(Into::into)
+            });
+            if let Err(e) = written {
+                eprintln!("weather cache: can't write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+impl<C, K> WeatherRequestClient for CachingClient<C, K>
+where
+    C: WeatherRequestClient,
+    K: Clock,
+{
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+        self.get_cached(wr).map(|cached| cached.forecast)
+    }
+    fn source(&self) -> String {
+        self.client.source()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::{Forecast, WeatherError, WeatherRequest, WeatherRequestClient};
+    use drafts::weather_cache::{CachingClient, Clock};
+    use drafts::weather_units::Units;
+    use std::cell::{Cell, RefCell};
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct ManualClock(Rc<Cell<u64>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    /// Answers every request with the fixture, or with `error` when set.
+    #[derive(Clone, Default)]
+    struct MockClient {
+        calls: Rc<Cell<usize>>,
+        error: Rc<RefCell<Option<u16>>>,
+        source: String,
+    }
+
+    impl WeatherRequestClient for MockClient {
+        fn get_forecast(&self, _: WeatherRequest) -> Result<Forecast, WeatherError> {
+            self.calls.set(self.calls.get() + 1);
+            match *self.error.borrow() {
+                Some(code) => Err(WeatherError::Api {
+                    code,
+                    message: String::new(),
+                }),
+                None => Forecast::from_json(
+                    include_str!("fixtures/owm_forecast.json"),
+                    Units::Standard,
+                ),
+            }
+        }
+        fn source(&self) -> String {
+            self.source.clone()
+        }
+    }
+
+    /// Waits for `gate` before answering requests for Rosario, telling
+    /// `asked` when it starts waiting.
+    struct SlowClient {
+        asked: Mutex<Sender<()>>,
+        gate: Mutex<Receiver<()>>,
+    }
+
+    impl WeatherRequestClient for SlowClient {
+        fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+            if wr == WeatherRequest::of("Rosario,ar") {
+                self.asked.lock().unwrap().send(()).unwrap();
+                self.gate.lock().unwrap().recv().unwrap();
+            }
+            Forecast::from_json(include_str!("fixtures/owm_forecast.json"), Units::Standard)
+        }
+    }
+
+    fn setup() -> (MockClient, ManualClock) {
+        (MockClient::default(), ManualClock(Rc::new(Cell::new(1000))))
+    }
+
+    fn request() -> WeatherRequest {
+        WeatherRequest::of("Buenos Aires,ar")
+    }
+
+    #[test]
+    fn answers_from_memory_until_the_ttl() {
+        let (mock, clock) = setup();
+        let client = CachingClient::new(mock.clone())
+            .clock(clock.clone())
+            .ttl(Duration::from_secs(600));
+
+        let first = client.get_cached(request()).unwrap();
+        assert_eq!(first.fetched_at, 1000);
+        clock.0.set(1599);
+        assert_eq!(client.get_cached(request()).unwrap(), first);
+        assert_eq!(mock.calls.get(), 1);
+
+        // Other requests are cached apart
+        client.get_forecast(request().units(Units::Imperial)).unwrap();
+        assert_eq!(mock.calls.get(), 2);
+
+        clock.0.set(1600);
+        assert_eq!(client.get_cached(request()).unwrap().fetched_at, 1600);
+        assert_eq!(mock.calls.get(), 3);
+    }
+
+    #[test]
+    fn serves_stale_when_unavailable() {
+        let (mock, clock) = setup();
+        let client = CachingClient::new(mock.clone())
+            .clock(clock.clone())
+            .ttl(Duration::from_secs(60));
+        client.get_forecast(request()).unwrap();
+
+        clock.0.set(5000);
+        *mock.error.borrow_mut() = Some(503);
+        let stale = client.get_cached(request()).unwrap();
+        assert!(stale.stale);
+        assert_eq!(stale.fetched_at, 1000);
+
+        // A bad key isn't the network failing
+        *mock.error.borrow_mut() = Some(401);
+        match client.get_cached(request()) {
+            Err(WeatherError::Api { code: 401, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // Nothing to fall back to
+        *mock.error.borrow_mut() = Some(503);
+        match client.get_cached(WeatherRequest::of("Rosario,ar")) {
+            Err(WeatherError::Api { code: 503, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let (mock, clock) = setup();
+        let client = CachingClient::new(mock.clone())
+            .clock(clock.clone())
+            .ttl(Duration::from_secs(0))
+            .calls_per_minute(3);
+        for second in 0..3 {
+            clock.0.set(1000 + second);
+            assert!(!client.get_cached(request()).unwrap().stale);
+        }
+        clock.0.set(1010);
+        let cached = client.get_cached(request()).unwrap();
+        assert!(cached.stale);
+        assert_eq!(cached.fetched_at, 1002);
+        match client.get_cached(WeatherRequest::of("Rosario,ar")) {
+            Err(WeatherError::RateLimited { retry_in: 50 }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(mock.calls.get(), 3);
+
+        clock.0.set(1060);
+        assert!(!client.get_cached(request()).unwrap().stale);
+        assert_eq!(mock.calls.get(), 4);
+    }
+
+    #[test]
+    fn persists_to_disk() {
+        let dir = env::temp_dir().join(format!("weather-cache-test-{}", ::std::process::id()));
+        let (mock, clock) = setup();
+        let client = CachingClient::new(mock.clone())
+            .clock(clock.clone())
+            .cache_dir(&dir);
+        let fetched = client.get_cached(request()).unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // A new process with the API down
+        let (offline, _) = setup();
+        *offline.error.borrow_mut() = Some(500);
+        let client = CachingClient::new(offline.clone())
+            .clock(clock.clone())
+            .cache_dir(&dir);
+        assert_eq!(client.get_cached(request()).unwrap(), fetched);
+        assert_eq!(offline.calls.get(), 0);
+        clock.0.set(1_000_000);
+        assert!(client.get_cached(request()).unwrap().stale);
+        assert_eq!(offline.calls.get(), 1);
+
+        // Another provider doesn't get the first one's answers
+        let other = MockClient {
+            source: "open-meteo".to_owned(),
+            ..MockClient::default()
+        };
+        let client = CachingClient::new(other.clone())
+            .clock(ManualClock(Rc::new(Cell::new(1000))))
+            .cache_dir(&dir);
+        client.get_cached(request()).unwrap();
+        assert_eq!(other.calls.get(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn slow_requests_dont_hold_up_cached_ones() {
+        let (asked, waiting) = channel();
+        let (open, gate) = channel();
+        let client = Arc::new(CachingClient::new(SlowClient {
+            asked: Mutex::new(asked),
+            gate: Mutex::new(gate),
+        }));
+        client.get_cached(request()).unwrap();
+
+        let slow = {
+            let client = client.clone();
+            thread::spawn(move || client.get_cached(WeatherRequest::of("Rosario,ar")).unwrap())
+        };
+        waiting.recv().unwrap();
+        let (answered, answer) = channel();
+        {
+            let client = client.clone();
+            thread::spawn(move || answered.send(client.get_cached(request()).unwrap()).unwrap());
+        }
+        assert!(!answer.recv_timeout(Duration::from_secs(5)).unwrap().stale);
+
+        open.send(()).unwrap();
+        assert!(!slow.join().unwrap().stale);
+    }
+}
@@ -0,0 +1,274 @@
+use drafts::weather::{Forecast, WeatherRequest, WeatherRequestClient};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How much the latest forecast can be trusted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Status {
+    /// Nothing fetched yet, still trying.
+    Pending,
+    Fresh,
+    /// There is a forecast, but it's older than it should be because the
+    /// latest attempts failed.
+    Stale,
+    /// Every attempt failed and there is no forecast to show.
+    Failed(String),
+}
+
+/// What the scene gets to read.
+#[derive(Clone, Debug)]
+pub struct Latest {
+    pub forecast: Option<Forecast>,
+    pub fetched_at: Option<Instant>,
+    pub last_error: Option<String>,
+    /// Whether the fetcher ever ran out of retries.
+    gave_up: bool,
+    stale_after: Duration,
+}
+
+impl Latest {
+    pub fn status(&self) -> Status {
+        match (self.fetched_at, &self.last_error) {
+            (Some(at), &None) if at.elapsed() < self.stale_after => Status::Fresh,
+            (Some(_), _) => Status::Stale,
+            (None, &Some(ref e)) if self.gave_up => Status::Failed(e.clone()),
+            (None, _) => Status::Pending,
+        }
+    }
+}
+
+/// Exponential backoff: `initial`, twice that, four times... up to `max`.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    /// Attempts after the first failure before waiting for the next refresh.
+    pub retries: u32,
+}
+
+impl Backoff {
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.initial
+            .checked_mul(1 << retry.min(20))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Schedule {
+    /// Time between successful fetches.
+    pub interval: Duration,
+    /// Age from which a forecast stops being fresh.
+    pub stale_after: Duration,
+    pub backoff: Backoff,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            interval: Duration::from_secs(10 * 60),
+            stale_after: Duration::from_secs(30 * 60),
+            backoff: Backoff {
+                initial: Duration::from_secs(2),
+                max: Duration::from_secs(5 * 60),
+                retries: 6,
+            },
+        }
+    }
+}
+
+/// Fetches forecasts on a thread of its own, so the frame loop never waits on
+/// the network, and keeps the latest one in a shared slot.
+///
+/// The thread stops when the fetcher is dropped.
+pub struct Fetcher {
+    slot: Arc<Mutex<Latest>>,
+    _stop: Sender<()>,
+}
+
+impl Fetcher {
+    pub fn spawn<C>(client: C, request: WeatherRequest, schedule: Schedule) -> Self
+    where
+        C: WeatherRequestClient + Send + 'static,
+    {
+        let slot = Arc::new(Mutex::new(Latest {
+            forecast: None,
+            fetched_at: None,
+            last_error: None,
+            gave_up: false,
+            stale_after: schedule.stale_after,
+        }));
+        let (stop, stopped) = channel::<()>();
+        let shared = slot.clone();
+        thread::spawn(move || {
+            let mut retry = 0;
+            loop {
+                let wait = match client.get_forecast(request.clone()) {
+                    Ok(forecast) => {
+                        let mut latest = shared.lock().unwrap();
+                        latest.forecast = Some(forecast);
+                        latest.fetched_at = Some(Instant::now());
+                        latest.last_error = None;
+                        retry = 0;
+                        schedule.interval
+                    }
+                    Err(e) => {
+                        let retrying = e.is_unavailable() && retry < schedule.backoff.retries;
+                        let mut latest = shared.lock().unwrap();
+                        latest.last_error = Some(e.to_string());
+                        if retrying {
+                            retry += 1;
+                            schedule.backoff.delay(retry - 1)
+                        } else {
+                            latest.gave_up = true;
+                            retry = 0;
+                            schedule.interval
+                        }
+                    }
+                };
+                // Sleeps, unless the fetcher is dropped meanwhile
+                match stopped.recv_timeout(wait) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => return,
+                }
+            }
+        });
+        Fetcher { slot, _stop: stop }
+    }
+    pub fn latest(&self) -> Latest {
+        self.slot.lock().unwrap().clone()
+    }
+    pub fn status(&self) -> Status {
+        self.slot.lock().unwrap().status()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::{OpenWeatherMap, WeatherConfig, WeatherRequest};
+    use drafts::weather_fetcher::{Backoff, Fetcher, Schedule, Status};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const FORECAST: &str = include_str!("fixtures/owm_forecast.json");
+    const UNAVAILABLE: &str = "{\"cod\":503,\"message\":\"try again later\"}";
+    const UNAUTHORIZED: &str = include_str!("fixtures/owm_unauthorized.json");
+
+    /// Answers the n-th request with the n-th body, and the last one forever
+    /// after. Returns its URL and how many requests it got.
+    fn stub_server(bodies: Vec<&'static str>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let body = bodies[n.min(bodies.len() - 1)];
+                let _ = write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, hits)
+    }
+
+    fn fetch_from(url: &str, schedule: Schedule) -> Fetcher {
+        let client = OpenWeatherMap::new(WeatherConfig::new("key").base_url(url));
+        Fetcher::spawn(client, WeatherRequest::of("Buenos Aires,ar"), schedule)
+    }
+
+    fn quick() -> Schedule {
+        Schedule {
+            interval: Duration::from_secs(60),
+            stale_after: Duration::from_secs(60),
+            backoff: Backoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(40),
+                retries: 3,
+            },
+        }
+    }
+
+    fn wait_for<F: Fn(&Status) -> bool>(fetcher: &Fetcher, done: F) -> Status {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let status = fetcher.status();
+            if done(&status) || Instant::now() > deadline {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let backoff = quick().backoff;
+        let delays: Vec<u64> = (0..5)
+            .map(|retry| backoff.delay(retry).subsec_nanos() as u64 / 1_000_000)
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 40, 40]);
+        assert_eq!(backoff.delay(100), Duration::from_millis(40));
+    }
+
+    #[test]
+    fn retries_until_it_gets_through() {
+        let (url, hits) = stub_server(vec![UNAVAILABLE, UNAVAILABLE, FORECAST]);
+        let fetcher = fetch_from(&url, quick());
+        assert_eq!(wait_for(&fetcher, |s| *s != Status::Pending), Status::Fresh);
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+        assert_eq!(fetcher.latest().forecast.unwrap().entries.len(), 3);
+    }
+
+    #[test]
+    fn fails_after_the_retries() {
+        let (url, hits) = stub_server(vec![UNAVAILABLE]);
+        let fetcher = fetch_from(&url, quick());
+        match wait_for(&fetcher, |s| *s != Status::Pending) {
+            Status::Failed(ref e) => assert!(e.contains("503"), "{}", e),
+            other => panic!("unexpected status: {:?}", other),
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 4);
+
+        // Retrying doesn't fix a bad key
+        let (url, hits) = stub_server(vec![UNAUTHORIZED]);
+        let fetcher = fetch_from(&url, quick());
+        match wait_for(&fetcher, |s| *s != Status::Pending) {
+            Status::Failed(ref e) => assert!(e.contains("401"), "{}", e),
+            other => panic!("unexpected status: {:?}", other),
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn goes_stale() {
+        let (url, _) = stub_server(vec![FORECAST, UNAVAILABLE]);
+        let fetcher = fetch_from(
+            &url,
+            Schedule {
+                interval: Duration::from_millis(50),
+                stale_after: Duration::from_secs(60),
+                ..quick()
+            },
+        );
+        assert_eq!(wait_for(&fetcher, |s| *s != Status::Pending), Status::Fresh);
+        // The refresh fails, but the old forecast is still there
+        assert_eq!(wait_for(&fetcher, |s| *s == Status::Stale), Status::Stale);
+        assert!(fetcher.latest().forecast.is_some());
+        assert!(fetcher.latest().last_error.unwrap().contains("503"));
+    }
+}
@@ -0,0 +1,196 @@
+use drafts::weather::{Entry, Forecast};
+
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The entries in the `days` days from the first one.
+pub fn first_days(forecast: &Forecast, days: u64) -> Forecast {
+    let until = forecast.entries.first().map_or(0, |e| e.time) + days * 24 * 60 * 60;
+    Forecast {
+        entries: forecast
+            .entries
+            .iter()
+            .filter(|e| e.time < until)
+            .cloned()
+            .collect(),
+        ..forecast.clone()
+    }
+}
+
+/// `Sat 20 15:00`, in UTC.
+pub fn format_time(time: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let days = time / (24 * 60 * 60);
+    let seconds = time % (24 * 60 * 60);
+    format!(
+        "{} {:02} {:02}:{:02}",
+        WEEKDAYS[(days % 7) as usize],
+        day_of_month(days),
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// The day of the month `days` after 1970-01-01.
+fn day_of_month(days: u64) -> u64 {
+    // Howard Hinnant's days_from_civil, backwards
+    let z = days + 719468;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    doy - (153 * mp + 2) / 5 + 1
+}
+
+/// Rain and snow, in mm.
+fn precipitation(entry: &Entry) -> f64 {
+    entry.rain.last_3h + entry.snow.last_3h
+}
+
+/// One line per 3 hour slot.
+pub fn table(forecast: &Forecast) -> String {
+    let mut out = format!(
+        "{}, {} (times in UTC)\n{:<12} {:>9} {:>9} {:>9} {:>5} {:>10} {:>6}  {}\n",
+        forecast.city.name,
+        forecast.city.country,
+        "time",
+        "temp",
+        "min",
+        "max",
+        "hum",
+        "wind",
+        "mm",
+        "conditions"
+    );
+    for entry in &forecast.entries {
+        out += &format!(
+            "{:<12} {:>9} {:>9} {:>9} {:>4}% {:>10} {:>6.1}  {}\n",
+            format_time(entry.time),
+            entry.main.temp.to_string(),
+            entry.main.temp_min.to_string(),
+            entry.main.temp_max.to_string(),
+            entry.main.humidity,
+            entry.wind.speed.to_string(),
+            precipitation(entry),
+            entry.condition().map_or("", |c| c.description.as_str())
+        );
+    }
+    out
+}
+
+/// One spark per value, from the lowest to `max`.
+pub fn sparkline(values: &[f64], max: f64) -> String {
+    values
+        .iter()
+        .map(|&v| {
+            if v <= 0.0 || max <= 0.0 {
+                ' '
+            } else {
+                let level = (v / max * SPARKS.len() as f64).ceil() as usize;
+                SPARKS[level.max(1).min(SPARKS.len()) - 1]
+            }
+        })
+        .collect()
+}
+
+/// Temperature as a line chart `height` rows high, a column per entry, and
+/// precipitation as a sparkline under it.
+pub fn chart(forecast: &Forecast, height: usize) -> String {
+    let temps: Vec<f64> = forecast.entries.iter().map(|e| e.main.temp.value()).collect();
+    if temps.is_empty() {
+        return String::from("no forecast\n");
+    }
+    let height = height.max(2);
+    let low = temps.iter().cloned().fold(f64::INFINITY, f64::min).floor();
+    let high = temps.iter().cloned().fold(f64::NEG_INFINITY, f64::max).ceil();
+    let step = (high - low).max(1.0) / (height - 1) as f64;
+    let row_of = |t: f64| ((t - low) / step).round() as usize;
+
+    let unit = forecast.entries[0].main.temp.to_string();
+    let unit = unit.split(' ').last().unwrap_or("");
+    let mut out = format!("{}, {}\n", forecast.city.name, forecast.city.country);
+    for row in (0..height).rev() {
+        out += &format!("{:>7.1} {} │", low + row as f64 * step, unit);
+        for (i, &t) in temps.iter().enumerate() {
+            let here = row_of(t);
+            let next = temps.get(i + 1).map_or(here, |&n| row_of(n));
+            out.push(if here == row {
+                '•'
+            } else if (here.min(next) < row && row < here.max(next))
+                || (row == next && next != here)
+            {
+                '·'
+            } else {
+                ' '
+            });
+        }
+        out.push('\n');
+    }
+
+    let mm: Vec<f64> = forecast.entries.iter().map(precipitation).collect();
+    let most = mm.iter().cloned().fold(0.0, f64::max);
+    out += &format!("{:>7} {} │{}\n", "mm", " ".repeat(unit.chars().count()), sparkline(&mm, most));
+    out += &format!(
+        "{:>8}{} └{}\n",
+        "",
+        " ".repeat(unit.chars().count()),
+        "─".repeat(temps.len())
+    );
+    if let (Some(first), Some(last)) = (forecast.entries.first(), forecast.entries.last()) {
+        out += &format!(
+            "{} to {} UTC, {:.1} mm at most\n",
+            format_time(first.time),
+            format_time(last.time),
+            most
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::Forecast;
+    use drafts::weather_format::{chart, first_days, format_time, sparkline, table};
+    use drafts::weather_units::Units;
+
+    fn forecast() -> Forecast {
+        Forecast::from_json(include_str!("fixtures/owm_forecast.json"), Units::Standard).unwrap()
+    }
+
+    #[test]
+    fn times() {
+        assert_eq!(format_time(0), "Thu 01 00:00");
+        assert_eq!(format_time(1540047600), "Sat 20 15:00");
+        assert_eq!(format_time(951782400), "Tue 29 00:00");
+    }
+
+    #[test]
+    fn forecast_table() {
+        let forecast = forecast().in_units(Units::Metric);
+        let table = table(&forecast);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("Buenos Aires, AR"));
+        assert!(lines[2].starts_with("Sat 20 12:00"));
+        assert!(lines[2].contains("18.0 °C"));
+        assert!(lines[3].contains("1.2"));
+        assert!(lines[3].ends_with("light rain"));
+
+        assert_eq!(first_days(&forecast, 1).entries.len(), 3);
+        assert_eq!(first_days(&forecast, 0).entries.len(), 0);
+    }
+
+    #[test]
+    fn charts() {
+        assert_eq!(sparkline(&[0.0, 1.0, 4.0, 8.0], 8.0), " ▁▄█");
+        assert_eq!(sparkline(&[0.1, 0.0], 0.0), "  ");
+
+        let chart = chart(&forecast().in_units(Units::Metric), 5);
+        let lines: Vec<&str> = chart.lines().collect();
+        assert_eq!(lines.len(), 9);
+        // 18°C and 16.5°C top the chart, then it drops to 0.75°C
+        assert!(lines[1].ends_with("│•• "));
+        assert!(lines[3].ends_with("│ · "));
+        assert!(lines[5].ends_with("│ ·•"));
+        assert!(lines[6].ends_with("│ █▂"));
+    }
+}
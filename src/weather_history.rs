@@ -0,0 +1,378 @@
+use drafts::weather::Forecast;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::ptr;
+
+const HEADER: &str = "recorded_at,city,time,celsius,humidity,wind,clouds,rain,snow,condition";
+const DAY: u64 = 24 * 60 * 60;
+/// Forecasts made less than a slot ahead are taken as what actually happened.
+const SLOT: u64 = 3 * 60 * 60;
+
+/// A forecast entry and when it was fetched, in metric units.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub recorded_at: u64,
+    /// The OpenWeatherMap city id, 0 when the place isn't a known city.
+    pub city: u64,
+    /// The start of the 3 hour slot forecast.
+    pub time: u64,
+    pub celsius: f64,
+    pub humidity: f64,
+    /// m/s
+    pub wind: f64,
+    pub clouds: f64,
+    /// mm in the 3 hours
+    pub rain: f64,
+    pub snow: f64,
+    pub condition: u16,
+}
+
+impl Record {
+    /// How long before its slot it was forecast, negative when after.
+    pub fn lead(&self) -> i64 {
+        self.time as i64 - self.recorded_at as i64
+    }
+    pub fn records(forecast: &Forecast, recorded_at: u64) -> Vec<Record> {
+        forecast
+            .entries
+            .iter()
+            .map(|entry| Record {
+                recorded_at,
+                city: forecast.city.id,
+                time: entry.time,
+                celsius: entry.main.temp.celsius(),
+                humidity: entry.main.humidity,
+                wind: entry.wind.speed.metres_per_second(),
+                clouds: entry.clouds.all,
+                rain: entry.rain.last_3h,
+                snow: entry.snow.last_3h,
+                condition: entry.condition().map_or(0, |c| c.id),
+            })
+            .collect()
+    }
+    fn to_csv(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{},{}",
+            self.recorded_at,
+            self.city,
+            self.time,
+            self.celsius,
+            self.humidity,
+            self.wind,
+            self.clouds,
+            self.rain,
+            self.snow,
+            self.condition
+        )
+    }
+    fn from_csv(line: &str) -> Option<Record> {
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() != 10 {
+            return None;
+        }
+        Some(Record {
+            recorded_at: fields[0].parse().ok()?,
+            city: fields[1].parse().ok()?,
+            time: fields[2].parse().ok()?,
+            celsius: fields[3].parse().ok()?,
+            humidity: fields[4].parse().ok()?,
+            wind: fields[5].parse().ok()?,
+            clouds: fields[6].parse().ok()?,
+            rain: fields[7].parse().ok()?,
+            snow: fields[8].parse().ok()?,
+            condition: fields[9].parse().ok()?,
+        })
+    }
+}
+
+/// Appends every forecast it is given to a CSV file, a row per entry.
+pub struct Recorder {
+    path: PathBuf,
+}
+
+impl Recorder {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Recorder { path: path.into() }
+    }
+    /// Returns how many rows were added.
+    pub fn record(&self, forecast: &Forecast, recorded_at: u64) -> io::Result<usize> {
+        let new = !self.path.exists();
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        if new {
+            writeln!(file, "{}", HEADER)?;
+        }
+        let records = Record::records(forecast, recorded_at);
+        for record in &records {
+            writeln!(file, "{}", record.to_csv())?;
+        }
+        Ok(records.len())
+    }
+    pub fn history(&self) -> Result<History, HistoryError> {
+        let file = OpenOptions::new().read(true).open(&self.path)?;
+        let mut records = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() || (i == 0 && line.trim() == HEADER) {
+                continue;
+            }
+            records.push(Record::from_csv(&line).ok_or(HistoryError::Syntax { line: i + 1 })?);
+        }
+        Ok(History::new(records))
+    }
+}
+
+#[derive(Debug)]
+pub enum HistoryError {
+    Io(io::Error),
+    /// A row that isn't a record, numbered from 1.
+    Syntax { line: usize },
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HistoryError::Io(ref e) => write!(f, "{}", e),
+            HistoryError::Syntax { line } => write!(f, "line {} isn't a weather record", line),
+        }
+    }
+}
+
+impl Error for HistoryError {}
+
+impl From<io::Error> for HistoryError {
+    fn from(e: io::Error) -> Self {
+        HistoryError::Io(e)
+    }
+}
+
+/// The temperatures observed a day, UTC.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DailySummary {
+    /// Days since the Unix epoch.
+    pub day: u64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// How far off forecasts made some time ahead were, in °C.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ForecastError {
+    pub samples: usize,
+    /// Positive when forecasts run warm.
+    pub bias: f64,
+    pub mean_absolute: f64,
+    pub rms: f64,
+}
+
+/// Recorded forecasts of one or more cities.
+///
+/// What actually happened in a slot is taken to be the last forecast of it,
+/// if it was made less than 3 hours ahead.
+pub struct History {
+    records: Vec<Record>,
+}
+
+impl History {
+    pub fn new(records: Vec<Record>) -> Self {
+        History { records }
+    }
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+    /// The last forecast of each slot of `city` made less than a slot ahead,
+    /// by time.
+    pub fn actuals(&self, city: u64) -> BTreeMap<u64, &Record> {
+        let mut actuals: BTreeMap<u64, &Record> = BTreeMap::new();
+        for record in self.records.iter().filter(|r| r.city == city) {
+            if record.lead() >= SLOT as i64 {
+                continue;
+            }
+            let later = actuals
+                .get(&record.time)
+                .map_or(true, |actual| record.recorded_at >= actual.recorded_at);
+            if later {
+                actuals.insert(record.time, record);
+            }
+        }
+        actuals
+    }
+    pub fn daily(&self, city: u64) -> Vec<DailySummary> {
+        let mut days: BTreeMap<u64, Vec<f64>> = BTreeMap::new();
+        for (time, actual) in self.actuals(city) {
+            days.entry(time / DAY).or_insert_with(Vec::new).push(actual.celsius);
+        }
+        days.into_iter()
+            .map(|(day, temps)| DailySummary {
+                day,
+                min: temps.iter().cloned().fold(f64::INFINITY, f64::min),
+                max: temps.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                mean: temps.iter().sum::<f64>() / temps.len() as f64,
+            })
+            .collect()
+    }
+    /// Compares what was forecast between `lead_hours` and `lead_hours + 3`
+    /// hours ahead with what happened, leaving out the records taken as what
+    /// happened. None without anything to compare.
+    pub fn forecast_error(&self, city: u64, lead_hours: u64) -> Option<ForecastError> {
+        let actuals = self.actuals(city);
+        let from = (lead_hours * 60 * 60) as i64;
+        let errors: Vec<f64> = self.records
+            .iter()
+            .filter(|r| r.city == city && r.lead() >= from && r.lead() < from + SLOT as i64)
+            .filter_map(|r| {
+                actuals
+                    .get(&r.time)
+                    .filter(|&&actual| !ptr::eq(r, actual))
+                    .map(|actual| r.celsius - actual.celsius)
+            })
+            .collect();
+        if errors.is_empty() {
+            return None;
+        }
+        let n = errors.len() as f64;
+        Some(ForecastError {
+            samples: errors.len(),
+            bias: errors.iter().sum::<f64>() / n,
+            mean_absolute: errors.iter().map(|e| e.abs()).sum::<f64>() / n,
+            rms: (errors.iter().map(|e| e * e).sum::<f64>() / n).sqrt(),
+        })
+    }
+    /// How often it rained in the slots starting at each hour of the day, UTC.
+    /// None for hours no slot starts at.
+    pub fn rain_probability_by_hour(&self, city: u64) -> Vec<Option<f64>> {
+        let mut counts = vec![(0, 0); 24];
+        for (time, actual) in self.actuals(city) {
+            let hour = (time % DAY / 3600) as usize;
+            counts[hour].0 += (actual.rain > 0.0) as usize;
+            counts[hour].1 += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(rainy, all)| if all == 0 { None } else { Some(rainy as f64 / all as f64) })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::Forecast;
+    use drafts::weather_history::{History, HistoryError, Record, Recorder};
+    use drafts::weather_units::Units;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+
+    const HOUR: u64 = 60 * 60;
+    /// 2018-10-20 00:00 UTC
+    const START: u64 = 1539993600;
+
+    fn record(recorded_at: u64, time: u64, celsius: f64, rain: f64) -> Record {
+        Record {
+            recorded_at,
+            city: 3435910,
+            time,
+            celsius,
+            humidity: 70.0,
+            wind: 3.0,
+            clouds: if rain > 0.0 { 90.0 } else { 10.0 },
+            rain,
+            snow: 0.0,
+            condition: if rain > 0.0 { 500 } else { 800 },
+        }
+    }
+
+    /// Two days where it is 10°C plus the hour, raining at 15:00 on the
+    /// first day and at 15:00 and 18:00 on the second. Every slot is also
+    /// forecast a day ahead 2 degrees too warm, and 2 days ahead 1 degree
+    /// too cold.
+    fn synthetic() -> History {
+        let mut records = Vec::new();
+        for slot in 0..16 {
+            let time = START + slot * 3 * HOUR;
+            let celsius = 10.0 + (slot % 8 * 3) as f64;
+            let hour = slot % 8 * 3;
+            let rain = if hour == 15 || (slot >= 8 && hour == 18) { 2.0 } else { 0.0 };
+            records.push(record(time - 2 * 24 * HOUR, time, celsius - 1.0, 0.0));
+            records.push(record(time - 24 * HOUR, time, celsius + 2.0, rain));
+            // A nowcast superseded by a later one
+            records.push(record(time - 2 * HOUR, time, celsius + 5.0, rain));
+            records.push(record(time - HOUR, time, celsius, rain));
+        }
+        History::new(records)
+    }
+
+    #[test]
+    fn daily_summaries() {
+        let daily = synthetic().daily(3435910);
+        assert_eq!(daily.len(), 2);
+        assert_eq!(daily[0].day, START / (24 * HOUR));
+        assert_eq!(daily[0].min, 10.0);
+        assert_eq!(daily[0].max, 31.0);
+        assert_eq!(daily[0].mean, 20.5);
+        assert_eq!(daily[1].day, daily[0].day + 1);
+        assert!(synthetic().daily(1).is_empty());
+    }
+
+    #[test]
+    fn errors_by_lead_time() {
+        let history = synthetic();
+        let day_ahead = history.forecast_error(3435910, 24).unwrap();
+        assert_eq!(day_ahead.samples, 16);
+        assert_eq!(day_ahead.bias, 2.0);
+        assert_eq!(day_ahead.rms, 2.0);
+
+        let two_days = history.forecast_error(3435910, 47).unwrap();
+        assert_eq!(two_days.bias, -1.0);
+        assert_eq!(two_days.mean_absolute, 1.0);
+
+        // Only the earlier nowcast counts, the later one is the actual
+        let nowcasts = history.forecast_error(3435910, 0).unwrap();
+        assert_eq!(nowcasts.samples, 16);
+        assert_eq!(nowcasts.mean_absolute, 5.0);
+        assert_eq!(history.forecast_error(3435910, 12), None);
+    }
+
+    #[test]
+    fn rain_by_hour() {
+        let rain = synthetic().rain_probability_by_hour(3435910);
+        assert_eq!(rain.len(), 24);
+        assert_eq!(rain[0], Some(0.0));
+        assert_eq!(rain[1], None);
+        assert_eq!(rain[15], Some(1.0));
+        assert_eq!(rain[18], Some(0.5));
+    }
+
+    #[test]
+    fn record_to_csv() {
+        let path = env::temp_dir().join(format!("weather-history-test-{}.csv", ::std::process::id()));
+        let _ = fs::remove_file(&path);
+        let forecast =
+            Forecast::from_json(include_str!("fixtures/owm_forecast.json"), Units::Standard)
+                .unwrap();
+        let recorder = Recorder::new(&path);
+        assert_eq!(recorder.record(&forecast, 1540036000).unwrap(), 3);
+        assert_eq!(recorder.record(&forecast.in_units(Units::Imperial), 1540040000).unwrap(), 3);
+
+        let history = recorder.history().unwrap();
+        assert_eq!(history.records().len(), 6);
+        let rainy = &history.records()[4];
+        assert_eq!(rainy.time, 1540047600);
+        assert!((rainy.celsius - 16.5).abs() < 1e-9);
+        assert!((rainy.wind - 6.2).abs() < 1e-9);
+        assert_eq!(rainy.rain, 1.25);
+        assert_eq!(rainy.condition, 500);
+        assert_eq!(history.actuals(3435910).len(), 2);
+
+        writeln!(File::create(&path).unwrap(), "1,2,3\n").unwrap();
+        match recorder.history() {
+            Err(HistoryError::Syntax { line: 1 }) => (),
+            other => panic!("unexpected result: {:?}", other.map(|h| h.records().len())),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+use drafts::weather::Coordinates;
+use std::fmt;
+
+/// A place to ask the weather for, in any of the ways the API understands.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Location {
+    /// A city name, optionally with its ISO 3166 country code.
+    City { name: String, country: Option<String> },
+    /// An OpenWeatherMap city id, the least ambiguous way to name a city.
+    Id(u64),
+    Coordinates(Coordinates),
+    Zip { code: String, country: String },
+}
+
+impl Location {
+    pub fn city(name: &str, country: &str) -> Self {
+        Location::City {
+            name: name.to_owned(),
+            country: Some(country.to_owned()),
+        }
+    }
+    pub fn at(lat: f64, lon: f64) -> Self {
+        Location::Coordinates(Coordinates { lat, lon })
+    }
+    pub fn zip(code: &str, country: &str) -> Self {
+        Location::Zip {
+            code: code.to_owned(),
+            country: country.to_owned(),
+        }
+    }
+    /// The query parameters naming this location.
+    pub fn to_api_params(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            Location::City {
+                ref name,
+                country: Some(ref country),
+            } => vec![("q", format!("{},{}", name, country.to_lowercase()))],
+            Location::City { ref name, .. } => vec![("q", name.clone())],
+            Location::Id(id) => vec![("id", id.to_string())],
+            Location::Coordinates(Coordinates { lat, lon }) => {
+                vec![("lat", lat.to_string()), ("lon", lon.to_string())]
+            }
+            Location::Zip {
+                ref code,
+                ref country,
+            } => vec![("zip", format!("{},{}", code, country.to_lowercase()))],
+        }
+    }
+    /// Swaps city names for the id of the city in `table` with that name, up
+    /// to case and accents. Other names are left for the API to look up, so
+    /// a typo or an unlisted city can't turn into a different city.
+    pub fn resolve(self, table: &CityTable) -> Self {
+        match self {
+            Location::City {
+                ref name,
+                ref country,
+            } => match table.exact(name, country.as_ref().map(|c| c.as_str())) {
+                Some(city) => Location::Id(city.id),
+                None => self.clone(),
+            },
+            other => other,
+        }
+    }
+}
+
+/// Reads how people write places: `"Buenos Aires,ar"`, `"3435910"`,
+/// `"-34.61,-58.38"` or `"zip:94040,us"`.
+impl<'a> From<&'a str> for Location {
+    fn from(place: &str) -> Self {
+        let place = place.trim();
+        if place.starts_with("zip:") {
+            let mut parts = place[4..].splitn(2, ',');
+            let code = parts.next().unwrap_or("").trim();
+            let country = parts.next().unwrap_or("us").trim();
+            return Location::zip(code, country);
+        }
+        if let Ok(id) = place.parse() {
+            return Location::Id(id);
+        }
+        let mut parts = place.splitn(2, ',');
+        let name = parts.next().unwrap_or("").trim();
+        let rest = parts.next().map(|rest| rest.trim());
+        if let (Ok(lat), Some(Ok(lon))) = (name.parse(), rest.map(|r| r.parse())) {
+            return Location::at(lat, lon);
+        }
+        Location::City {
+            name: name.to_owned(),
+            country: rest.map(|country| country.to_owned()),
+        }
+    }
+}
+
+impl From<Coordinates> for Location {
+    fn from(coordinates: Coordinates) -> Self {
+        Location::Coordinates(coordinates)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Location::City {
+                ref name,
+                country: Some(ref country),
+            } => write!(f, "{}, {}", name, country.to_uppercase()),
+            Location::City { ref name, .. } => write!(f, "{}", name),
+            Location::Id(id) => write!(f, "city #{}", id),
+            Location::Coordinates(Coordinates { lat, lon }) => write!(f, "{}, {}", lat, lon),
+            Location::Zip {
+                ref code,
+                ref country,
+            } => write!(f, "{} ({})", code, country.to_uppercase()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CityRecord {
+    pub id: u64,
+    pub name: String,
+    pub country: String,
+    pub coordinates: Coordinates,
+}
+
+/// Cities known without asking the API, looked up forgiving typos, case and
+/// accents.
+pub struct CityTable {
+    cities: Vec<CityRecord>,
+}
+
+impl CityTable {
+    /// The cities in `cities.csv`, shipped with the binary.
+    pub fn bundled() -> Self {
+        CityTable::from_csv(include_str!("cities.csv"))
+    }
+    /// Reads `id,name,country,lat,lon` lines, skipping the header and
+    /// anything that doesn't parse.
+    pub fn from_csv(source: &str) -> Self {
+        let cities = source
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+                if fields.len() != 5 {
+                    return None;
+                }
+                Some(CityRecord {
+                    id: fields[0].parse().ok()?,
+                    name: fields[1].to_owned(),
+                    country: fields[2].to_uppercase(),
+                    coordinates: Coordinates {
+                        lat: fields[3].parse().ok()?,
+                        lon: fields[4].parse().ok()?,
+                    },
+                })
+            })
+            .collect();
+        CityTable { cities }
+    }
+    pub fn len(&self) -> usize {
+        self.cities.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cities.is_empty()
+    }
+    pub fn get(&self, id: u64) -> Option<&CityRecord> {
+        self.cities.iter().find(|city| city.id == id)
+    }
+    /// The city called `name`, up to case, accents and punctuation.
+    pub fn exact(&self, name: &str, country: Option<&str>) -> Option<&CityRecord> {
+        self.search(name, country, 1)
+            .into_iter()
+            .find(|&(_, distance)| distance == 0)
+            .map(|(city, _)| city)
+    }
+    /// The best match for `name`, as long as it's close enough to be a typo.
+    pub fn find(&self, name: &str, country: Option<&str>) -> Option<&CityRecord> {
+        self.search(name, country, 1)
+            .into_iter()
+            .next()
+            .filter(|&(city, distance)| distance <= tolerance(&normalise(&city.name)))
+            .map(|(city, _)| city)
+    }
+    /// The `limit` closest cities to `name`, with their edit distance.
+    pub fn search(&self, name: &str, country: Option<&str>, limit: usize) -> Vec<(&CityRecord, usize)> {
+        let name = normalise(name);
+        let mut matches: Vec<(&CityRecord, usize)> = self.cities
+            .iter()
+            .filter(|city| country.map_or(true, |c| city.country.eq_ignore_ascii_case(c)))
+            .map(|city| (city, distance(&name, &normalise(&city.name))))
+            .collect();
+        matches.sort_by_key(|&(_, distance)| distance);
+        matches.truncate(limit);
+        matches
+    }
+}
+
+/// Edits allowed before a name stops being a typo of `name`.
+fn tolerance(name: &str) -> usize {
+    (name.chars().count() / 4).max(1)
+}
+
+/// Lower case, without accents or punctuation.
+fn normalise(name: &str) -> String {
+    name.chars()
+        .flat_map(|c| c.to_lowercase())
+        .map(|c| match c {
+            'á' | 'à' | 'ä' | 'â' | 'ã' => 'a',
+            'é' | 'è' | 'ë' | 'ê' => 'e',
+            'í' | 'ì' | 'ï' | 'î' => 'i',
+            'ó' | 'ò' | 'ö' | 'ô' | 'õ' => 'o',
+            'ú' | 'ù' | 'ü' | 'û' => 'u',
+            'ñ' => 'n',
+            'ç' => 'c',
+            c => c,
+        })
+        .filter(|c| c.is_alphanumeric() || *c == ' ')
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Levenshtein distance.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather_location::{CityTable, Location};
+
+    #[test]
+    fn parse_places() {
+        assert_eq!(Location::from("Buenos Aires,ar"), Location::city("Buenos Aires", "ar"));
+        assert_eq!(
+            Location::from("Rosario"),
+            Location::City {
+                name: "Rosario".to_owned(),
+                country: None,
+            }
+        );
+        assert_eq!(Location::from("3435910"), Location::Id(3435910));
+        assert_eq!(Location::from("-34.61, -58.38"), Location::at(-34.61, -58.38));
+        assert_eq!(Location::from("zip:94040,us"), Location::zip("94040", "us"));
+    }
+
+    #[test]
+    fn api_params() {
+        assert_eq!(
+            Location::city("Buenos Aires", "AR").to_api_params(),
+            vec![("q", "Buenos Aires,ar".to_owned())]
+        );
+        assert_eq!(Location::Id(7).to_api_params(), vec![("id", "7".to_owned())]);
+        assert_eq!(
+            Location::at(-34.5, -58.25).to_api_params(),
+            vec![("lat", "-34.5".to_owned()), ("lon", "-58.25".to_owned())]
+        );
+        assert_eq!(
+            Location::zip("1425", "AR").to_api_params(),
+            vec![("zip", "1425,ar".to_owned())]
+        );
+    }
+
+    #[test]
+    fn fuzzy_lookup() {
+        let table = CityTable::bundled();
+        assert!(table.len() > 30);
+        assert!(CityTable::from_csv("").is_empty());
+        assert_eq!(table.find("Buenos Aires", Some("ar")).unwrap().id, 3435910);
+        assert_eq!(table.find("buenos  aries", None).unwrap().id, 3435910);
+        assert_eq!(table.find("Cordoba", None).unwrap().name, "Córdoba");
+        assert_eq!(table.find("sao paulo", Some("BR")).unwrap().id, 3448439);
+        assert!(table.find("Sao Paulo", Some("AR")).is_none());
+        assert!(table.find("Atlantis", None).is_none());
+
+        assert_eq!(table.exact("montevideo", Some("uy")).unwrap().id, 3441575);
+        assert!(table.exact("Montevido", Some("uy")).is_none());
+
+        // Only exact names become ids, typos go to the API as written
+        assert_eq!(
+            Location::from("Montevideo,uy").resolve(&table),
+            Location::Id(3441575)
+        );
+        let typo = Location::from("Montevido,uy");
+        assert_eq!(typo.clone().resolve(&table), typo);
+        let unknown = Location::from("Villa Gesell,ar");
+        assert_eq!(unknown.clone().resolve(&table), unknown);
+    }
+}
@@ -0,0 +1,450 @@
+use drafts::weather::{Clouds, Condition, Coordinates, Entry, Forecast, ForecastCity,
+                      Measurements, OpenWeatherMap, Provider, Volume, WeatherConfig,
+                      WeatherError, WeatherRequest, WeatherRequestClient, Wind};
+use drafts::weather_location::{CityTable, Location};
+use drafts::weather_units::Units;
+use reqwest;
+use serde::de::Error;
+use serde_json::{self, Value};
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// The client for the provider `config` picks.
+pub fn connect(config: WeatherConfig) -> Box<WeatherRequestClient + Send> {
+    match config.provider.clone() {
+        Provider::OpenWeatherMap => Box::new(OpenWeatherMap::new(config)),
+        Provider::OpenMeteo => Box::new(OpenMeteo::new(config)),
+        Provider::File(path) => Box::new(FileProvider::new(path)),
+    }
+}
+
+const HOURLY: &str = "temperature_2m,relative_humidity_2m,pressure_msl,cloud_cover,\
+                      wind_speed_10m,wind_direction_10m,rain,snowfall,weather_code";
+
+/// The Open-Meteo forecast API, whose hourly series are folded into 3 hour
+/// entries like OpenWeatherMap's.
+///
+/// It only takes coordinates, so other locations are looked up in the
+/// bundled city table.
+pub struct OpenMeteo {
+    http: reqwest::Client,
+    config: WeatherConfig,
+    cities: CityTable,
+}
+
+impl OpenMeteo {
+    pub fn new(config: WeatherConfig) -> Self {
+        OpenMeteo {
+            http: reqwest::Client::new(),
+            config,
+            cities: CityTable::bundled(),
+        }
+    }
+    fn city(&self, location: &Location) -> Result<ForecastCity, WeatherError> {
+        let record = match *location {
+            Location::Coordinates(coord) => {
+                return Ok(ForecastCity {
+                    id: 0,
+                    name: location.to_string(),
+                    country: String::new(),
+                    coord,
+                })
+            }
+            Location::Id(id) => self.cities.get(id),
+            Location::City {
+                ref name,
+                ref country,
+            } => {
+                let country = country.as_ref().map(|c| c.as_str());
+                self.cities.exact(name, country).or_else(|| {
+                    // Open-Meteo can't look names up itself, so say which
+                    // city stands in for a typo
+                    let city = self.cities.find(name, country);
+                    if let Some(city) = city {
+                        eprintln!(
+                            "no city called {}, using {}, {}",
+                            location, city.name, city.country
+                        );
+                    }
+                    city
+                })
+            }
+            Location::Zip { .. } => None,
+        };
+        record
+            .map(|city| ForecastCity {
+                id: city.id,
+                name: city.name.clone(),
+                country: city.country.clone(),
+                coord: city.coordinates,
+            })
+            .ok_or_else(|| WeatherError::UnknownPlace(location.to_string()))
+    }
+    /// Reads an answer asked with `units`, which must be metric or imperial:
+    /// Open-Meteo doesn't do Kelvin.
+    pub fn parse(body: &str, city: ForecastCity, units: Units) -> Result<Forecast, WeatherError> {
+        let value: Value = serde_json::from_str(body)?;
+        if value.get("error").and_then(|e| e.as_bool()) == Some(true) {
+            return Err(WeatherError::Api {
+                code: 400,
+                message: value
+                    .get("reason")
+                    .and_then(|r| r.as_str())
+                    .unwrap_or("")
+                    .to_owned(),
+            });
+        }
+        let hourly: Hourly = serde_json::from_value(value["hourly"].clone())?;
+        hourly.check()?;
+        let entries = (0..hourly.time.len())
+            .step_by(3)
+            .filter_map(|start| hourly.entry(start, (start + 3).min(hourly.time.len()), units))
+            .collect();
+        Ok(Forecast {
+            city,
+            units,
+            entries,
+        })
+    }
+}
+
+impl WeatherRequestClient for OpenMeteo {
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+        let city = self.city(wr.location())?;
+        let units = wr.requested_units();
+        let asked = match units {
+            Units::Imperial => Units::Imperial,
+            Units::Metric | Units::Standard => Units::Metric,
+        };
+        let (temperature_unit, wind_speed_unit) = match asked {
+            Units::Imperial => ("fahrenheit", "mph"),
+            _ => ("celsius", "ms"),
+        };
+        let body = self.http.get(&self.config.endpoint("forecast"))
+            .query(&[("latitude", city.coord.lat.to_string()),
+                     ("longitude", city.coord.lon.to_string()),
+                     ("hourly", HOURLY.to_owned()),
+                     ("temperature_unit", temperature_unit.to_owned()),
+                     ("wind_speed_unit", wind_speed_unit.to_owned()),
+                     ("timeformat", "unixtime".to_owned()),
+                     ("forecast_days", "5".to_owned())])
+            .send()?
+            .text()?;
+        Ok(OpenMeteo::parse(&body, city, asked)?.in_units(units))
+    }
+    fn source(&self) -> String {
+        format!("open-meteo {}", self.config.base_url)
+    }
+}
+
+/// Hours Open-Meteo has no value for are `null`.
+#[derive(Deserialize)]
+struct Hourly {
+    time: Vec<u64>,
+    temperature_2m: Vec<Option<f64>>,
+    relative_humidity_2m: Vec<Option<f64>>,
+    pressure_msl: Vec<Option<f64>>,
+    cloud_cover: Vec<Option<f64>>,
+    wind_speed_10m: Vec<Option<f64>>,
+    wind_direction_10m: Vec<Option<f64>>,
+    rain: Vec<Option<f64>>,
+    /// Centimetres
+    snowfall: Vec<Option<f64>>,
+    weather_code: Vec<Option<u8>>,
+}
+
+impl Hourly {
+    /// Fails unless every series has a value, or a `null`, for every hour.
+    fn check(&self) -> Result<(), serde_json::Error> {
+        let lengths = [
+            ("temperature_2m", self.temperature_2m.len()),
+            ("relative_humidity_2m", self.relative_humidity_2m.len()),
+            ("pressure_msl", self.pressure_msl.len()),
+            ("cloud_cover", self.cloud_cover.len()),
+            ("wind_speed_10m", self.wind_speed_10m.len()),
+            ("wind_direction_10m", self.wind_direction_10m.len()),
+            ("rain", self.rain.len()),
+            ("snowfall", self.snowfall.len()),
+            ("weather_code", self.weather_code.len()),
+        ];
+        match lengths.iter().find(|&&(_, len)| len != self.time.len()) {
+            Some(&(name, len)) => Err(serde_json::Error::custom(format!(
+                "hourly {} has {} values for {} hours",
+                name,
+                len,
+                self.time.len()
+            ))),
+            None => Ok(()),
+        }
+    }
+    /// The hours from `start` to `end` as one entry: what it's like at the
+    /// start, the extremes, the precipitation added up, and the worst weather.
+    ///
+    /// Missing hours are left out of the extremes and the sums, and the
+    /// entry is dropped when the start has no temperature, pressure,
+    /// humidity, clouds or wind. Without a known weather code it has no
+    /// condition.
+    fn entry(&self, start: usize, end: usize, units: Units) -> Option<Entry> {
+        let temps: Vec<f64> = present(&self.temperature_2m[start..end]).collect();
+        let min = temps.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = temps.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let worst = present(&self.weather_code[start..end])
+            .filter_map(|code| {
+                let condition = condition(code);
+                if condition.is_none() {
+                    eprintln!("open-meteo: unknown weather code {}", code);
+                }
+                condition
+            })
+            .max_by_key(|&(severity, _)| severity)
+            .map(|(_, condition)| condition);
+        Some(Entry {
+            time: self.time[start],
+            main: Measurements {
+                temp: units.temperature(self.temperature_2m[start]?),
+                temp_min: units.temperature(min),
+                temp_max: units.temperature(max),
+                pressure: self.pressure_msl[start]?,
+                humidity: self.relative_humidity_2m[start]?,
+            },
+            weather: worst.into_iter().collect(),
+            clouds: Clouds {
+                all: self.cloud_cover[start]?,
+            },
+            wind: Wind {
+                speed: units.speed(self.wind_speed_10m[start]?),
+                deg: self.wind_direction_10m[start]?,
+            },
+            rain: Volume {
+                last_3h: present(&self.rain[start..end]).sum(),
+            },
+            snow: Volume {
+                last_3h: present(&self.snowfall[start..end]).sum::<f64>() * 10.0,
+            },
+        })
+    }
+}
+
+/// The hours of a series that have a value.
+fn present<'a, T: Copy>(series: &'a [Option<T>]) -> impl Iterator<Item = T> + 'a {
+    series.iter().filter_map(|v| *v)
+}
+
+/// The OpenWeatherMap condition closest to a WMO weather code, after how
+/// bad it is. WMO codes group weather by kind, so their order says nothing
+/// about that. None for codes WMO doesn't define.
+fn condition(wmo: u8) -> Option<(u8, Condition)> {
+    let (severity, id, main, description) = match wmo {
+        0 => (0, 800, "Clear", "clear sky"),
+        1 => (1, 801, "Clouds", "few clouds"),
+        2 => (2, 802, "Clouds", "scattered clouds"),
+        3 => (3, 804, "Clouds", "overcast clouds"),
+        45 | 48 => (4, 741, "Fog", "fog"),
+        51 | 53 | 55 => (5, 300, "Drizzle", "drizzle"),
+        61 => (6, 500, "Rain", "light rain"),
+        80 => (7, 520, "Rain", "light intensity shower rain"),
+        71 | 77 => (8, 600, "Snow", "light snow"),
+        85 => (9, 620, "Snow", "light shower snow"),
+        63 => (10, 501, "Rain", "moderate rain"),
+        81 => (11, 521, "Rain", "shower rain"),
+        73 => (12, 601, "Snow", "snow"),
+        86 => (13, 621, "Snow", "shower snow"),
+        65 => (14, 502, "Rain", "heavy intensity rain"),
+        82 => (15, 522, "Rain", "heavy intensity shower rain"),
+        75 => (16, 602, "Snow", "heavy snow"),
+        56 | 57 | 66 | 67 => (17, 511, "Rain", "freezing rain"),
+        95 => (18, 211, "Thunderstorm", "thunderstorm"),
+        96 | 99 => (19, 202, "Thunderstorm", "thunderstorm with heavy rain"),
+        _ => return None,
+    };
+    Some((
+        severity,
+        Condition {
+            id,
+            main: main.to_owned(),
+            description: description.to_owned(),
+            icon: String::new(),
+        },
+    ))
+}
+
+/// Answers every request with an OpenWeatherMap forecast recorded in a file,
+/// in standard units, converted to the units asked.
+pub struct FileProvider {
+    path: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileProvider { path: path.into() }
+    }
+}
+
+impl WeatherRequestClient for FileProvider {
+    fn get_forecast(&self, wr: WeatherRequest) -> Result<Forecast, WeatherError> {
+        let mut body = String::new();
+        File::open(&self.path)?.read_to_string(&mut body)?;
+        Ok(Forecast::from_json(&body, Units::Standard)?.in_units(wr.requested_units()))
+    }
+    fn source(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::{Coordinates, ForecastCity, Provider, WeatherConfig, WeatherError,
+                          WeatherRequest, WeatherRequestClient};
+    use drafts::weather_providers::{connect, FileProvider, OpenMeteo};
+    use drafts::weather_units::{Temperature, Units};
+    use std::path::{Path, PathBuf};
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join(file!())
+            .with_file_name("fixtures")
+            .join(name)
+    }
+
+    fn buenos_aires() -> ForecastCity {
+        ForecastCity {
+            id: 3435910,
+            name: "Buenos Aires".to_owned(),
+            country: "AR".to_owned(),
+            coord: Coordinates {
+                lat: -34.6132,
+                lon: -58.3772,
+            },
+        }
+    }
+
+    #[test]
+    fn parse_open_meteo() {
+        let body = include_str!("fixtures/open_meteo_forecast.json");
+        let forecast = OpenMeteo::parse(body, buenos_aires(), Units::Metric).unwrap();
+        assert_eq!(forecast.entries.len(), 3);
+
+        let clear = &forecast.entries[0];
+        assert_eq!(clear.time, 1540036800);
+        assert_eq!(clear.main.temp, Temperature::Celsius(18.0));
+        assert_eq!(clear.main.temp_min, Temperature::Celsius(17.4));
+        assert_eq!(clear.main.temp_max, Temperature::Celsius(18.6));
+        assert_eq!(clear.condition().unwrap().id, 800);
+
+        let rainy = &forecast.entries[1];
+        assert_eq!(rainy.time, 1540047600);
+        assert_eq!(rainy.rain.last_3h, 1.25);
+        assert_eq!(rainy.clouds.all, 88.0);
+        assert_eq!(rainy.condition().unwrap().id, 501);
+
+        let snowy = &forecast.entries[2];
+        assert_eq!(snowy.condition().unwrap().main, "Snow");
+        assert!((snowy.snow.last_3h - 0.3).abs() < 1e-9);
+
+        match OpenMeteo::parse(
+            r#"{"error":true,"reason":"Latitude must be in range of -90 to 90°."}"#,
+            buenos_aires(),
+            Units::Metric,
+        ) {
+            Err(WeatherError::Api { code: 400, .. }) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_open_meteo_gaps() {
+        let hourly = |temperatures: &str| {
+            format!(
+                r#"{{"hourly": {{
+                    "time": [0, 3600, 7200, 10800, 14400, 18000],
+                    "temperature_2m": {},
+                    "relative_humidity_2m": [70, 70, 70, 70, 70, 70],
+                    "pressure_msl": [1013, 1013, 1013, 1013, 1013, 1013],
+                    "cloud_cover": [0, 0, 0, null, null, null],
+                    "wind_speed_10m": [5, 5, 5, 5, 5, 5],
+                    "wind_direction_10m": [90, 90, 90, 90, 90, 90],
+                    "rain": [null, 0.5, 0.5, null, null, null],
+                    "snowfall": [0, 0, 0, 0, 0, 0],
+                    "weather_code": [0, null, 61, null, null, null]
+                }}}}"#,
+                temperatures
+            )
+        };
+
+        // Hours past the end of the model come back as nulls
+        let forecast = OpenMeteo::parse(
+            &hourly("[18.0, null, 12.0, null, null, null]"),
+            buenos_aires(),
+            Units::Metric,
+        ).unwrap();
+        assert_eq!(forecast.entries.len(), 1);
+        let entry = &forecast.entries[0];
+        assert_eq!(entry.main.temp_min, Temperature::Celsius(12.0));
+        assert_eq!(entry.main.temp_max, Temperature::Celsius(18.0));
+        assert_eq!(entry.rain.last_3h, 1.0);
+        assert_eq!(entry.condition().unwrap().id, 500);
+
+        match OpenMeteo::parse(&hourly("[18.0, 17.0]"), buenos_aires(), Units::Metric) {
+            Err(WeatherError::Parse(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn worst_weather_of_each_entry() {
+        let body = r#"{"hourly": {
+            "time": [0, 3600, 7200, 10800, 14400, 18000, 21600, 25200, 28800],
+            "temperature_2m": [5, 5, 5, 5, 5, 5, 5, 5, 5],
+            "relative_humidity_2m": [90, 90, 90, 90, 90, 90, 90, 90, 90],
+            "pressure_msl": [1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000, 1000],
+            "cloud_cover": [100, 100, 100, 100, 100, 100, 100, 100, 100],
+            "wind_speed_10m": [2, 2, 2, 2, 2, 2, 2, 2, 2],
+            "wind_direction_10m": [0, 0, 0, 0, 0, 0, 0, 0, 0],
+            "rain": [0, 0, 0, 0, 0, 0, 0, 0, 0],
+            "snowfall": [0, 0, 0, 0, 0, 0, 0, 0, 0],
+            "weather_code": [65, 71, null, 3, 45, 3, 42, null, 42]
+        }}"#;
+        let forecast = OpenMeteo::parse(body, buenos_aires(), Units::Metric).unwrap();
+        // Heavy rain is worse than light snow, fog worse than overcast
+        assert_eq!(forecast.entries[0].condition().unwrap().id, 502);
+        assert_eq!(forecast.entries[1].condition().unwrap().id, 741);
+        // Unknown codes aren't clear skies
+        assert!(forecast.entries[2].condition().is_none());
+    }
+
+    #[test]
+    fn providers_agree() {
+        // Both fixtures describe the same afternoon
+        let open_meteo = OpenMeteo::parse(
+            include_str!("fixtures/open_meteo_forecast.json"),
+            buenos_aires(),
+            Units::Metric,
+        ).unwrap();
+        let recorded = FileProvider::new(fixture("owm_forecast.json"))
+            .get_forecast(WeatherRequest::of("Buenos Aires,ar").units(Units::Metric))
+            .unwrap();
+        assert_eq!(recorded.units, Units::Metric);
+        for (a, b) in open_meteo.entries.iter().zip(&recorded.entries) {
+            assert_eq!(a.time, b.time);
+            assert!((a.main.temp.celsius() - b.main.temp.celsius()).abs() < 0.01);
+            assert_eq!(a.condition().unwrap().main, b.condition().unwrap().main);
+        }
+    }
+
+    #[test]
+    fn offline_provider() {
+        let config = WeatherConfig::of(Provider::File(fixture("owm_forecast.json")));
+        let forecast = connect(config)
+            .get_forecast(WeatherRequest::of("anywhere").units(Units::Imperial))
+            .unwrap();
+        assert!((forecast.entries[0].main.temp.value() - 64.4).abs() < 1e-9);
+
+        match FileProvider::new(fixture("missing.json"))
+            .get_forecast(WeatherRequest::of("anywhere"))
+        {
+            Err(WeatherError::Io(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
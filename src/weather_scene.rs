@@ -0,0 +1,215 @@
+use drafts::visuals::equations::{blue_intensity, green_intensity, red_intensity};
+use drafts::weather::{Entry, Forecast, WeatherConfig, WeatherRequest, WeatherRequestClient};
+use drafts::weather_fetcher::{Fetcher, Schedule, Status};
+use drafts::weather_location::{CityTable, Location};
+use drafts::weather_providers::connect;
+use mursten::{Data, Updater};
+use nalgebra::Vector3;
+use std::env;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How a scene should look under some weather.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WeatherLook {
+    pub current_color: Vector3<f32>,
+    pub glow_amount: f32,
+    pub ray_proportion: f32,
+    pub light_intensity: f32,
+}
+
+impl WeatherLook {
+    pub fn of(entry: &Entry) -> Self {
+        let clouds = cloud_cover(entry);
+        WeatherLook {
+            current_color: color(entry.main.temp.celsius() as f32),
+            glow_amount: glow_amount(clouds),
+            ray_proportion: ray_proportion(entry.wind.speed.metres_per_second() as f32),
+            light_intensity: light_intensity(clouds, entry.rain.last_3h as f32),
+        }
+    }
+}
+
+/// The entry covering `now`, in seconds since the epoch, or the closest one.
+pub fn entry_at(forecast: &Forecast, now: u64) -> Option<&Entry> {
+    forecast
+        .entries
+        .iter()
+        .take_while(|entry| entry.time <= now)
+        .last()
+        .or_else(|| forecast.entries.first())
+}
+
+/// From 0 for a clear sky to 1 when overcast.
+pub fn cloud_cover(entry: &Entry) -> f32 {
+    (entry.clouds.all as f32 / 100.0).max(0.0).min(1.0)
+}
+
+/// Blue when freezing, through green, to red from 35°C on.
+pub fn color(celsius: f32) -> Vector3<f32> {
+    let cold = 1.0 - ((celsius + 5.0) / 40.0).max(0.0).min(1.0);
+    Vector3::new(
+        red_intensity(cold),
+        green_intensity(cold),
+        blue_intensity(cold),
+    )
+}
+
+/// Rays glow the most under a clear sky.
+pub fn glow_amount(cloud_cover: f32) -> f32 {
+    2.0 + 6.0 * (1.0 - cloud_cover)
+}
+
+/// Wind stretches the rays, from 2 when calm to 10 in a gale (20 m/s).
+pub fn ray_proportion(wind_speed: f32) -> f32 {
+    2.0 + 8.0 * (wind_speed / 20.0).max(0.0).min(1.0)
+}
+
+/// The sun through the clouds, dimmed further by rain.
+pub fn light_intensity(cloud_cover: f32, rain_mm: f32) -> f32 {
+    let rain = (rain_mm / 10.0).max(0.0).min(1.0);
+    (0.6 - 0.4 * cloud_cover) * (1.0 - 0.5 * rain)
+}
+
+/// Data that changes with the weather.
+pub trait OnWeather {
+    fn on_weather(&mut self, look: WeatherLook);
+}
+
+/// Reads the latest forecast a `Fetcher` got every tick and hands the look
+/// of the current entry to the data whenever it changes.
+pub struct WeatherUpdater {
+    fetcher: Fetcher,
+    last_status: Status,
+    last_look: Option<WeatherLook>,
+}
+
+impl WeatherUpdater {
+    pub fn new<C>(client: C, request: WeatherRequest, interval: Duration) -> Self
+    where
+        C: WeatherRequestClient + Send + 'static,
+    {
+        let schedule = Schedule {
+            interval,
+            ..Schedule::default()
+        };
+        WeatherUpdater {
+            fetcher: Fetcher::spawn(client, request, schedule),
+            last_status: Status::Pending,
+            last_look: None,
+        }
+    }
+    pub fn status(&self) -> Status {
+        self.last_status.clone()
+    }
+}
+
+/// An updater for the place in `WEATHER_PLACE`, Buenos Aires if unset,
+/// refreshing every ten minutes. None, saying why, when the weather config
+/// can't be loaded.
+pub fn weather_updater_from_env() -> Option<WeatherUpdater> {
+    let config = match WeatherConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Running without weather: {}", e);
+            return None;
+        }
+    };
+    let place = env::var("WEATHER_PLACE").unwrap_or_else(|_| "Buenos Aires,ar".to_owned());
+    let location = Location::from(place.as_str()).resolve(&CityTable::bundled());
+    Some(WeatherUpdater::new(
+        connect(config),
+        WeatherRequest::of(location),
+        Duration::from_secs(10 * 60),
+    ))
+}
+
+impl<B, D> Updater<B, D> for WeatherUpdater
+where
+    D: Data + OnWeather,
+{
+    fn update(&mut self, _: &mut B, data: &mut D) {
+        let latest = self.fetcher.latest();
+        let status = latest.status();
+        if status != self.last_status {
+            match (&status, &latest.last_error) {
+                (&Status::Stale, &Some(ref e)) | (&Status::Failed(ref e), _) => {
+                    eprintln!("weather: {}", e)
+                }
+                _ => (),
+            }
+            self.last_status = status;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let look = match latest.forecast {
+            Some(ref forecast) => entry_at(forecast, now).map(WeatherLook::of),
+            None => None,
+        };
+        if look.is_some() && look != self.last_look {
+            data.on_weather(look.unwrap());
+            self.last_look = look;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather::Forecast;
+    use drafts::weather_scene::{color, entry_at, glow_amount, light_intensity,
+                                ray_proportion, WeatherLook};
+    use drafts::weather_units::Units;
+
+    fn forecast() -> Forecast {
+        Forecast::from_json(include_str!("fixtures/owm_forecast.json"), Units::Standard).unwrap()
+    }
+
+    #[test]
+    fn looks_of_the_fixture() {
+        let forecast = forecast();
+        let clear = WeatherLook::of(&forecast.entries[0]);
+        let rainy = WeatherLook::of(&forecast.entries[1]);
+        let snowy = WeatherLook::of(&forecast.entries[2]);
+
+        assert_eq!(clear.glow_amount, 8.0);
+        assert!(rainy.glow_amount < clear.glow_amount);
+        assert_eq!(snowy.glow_amount, 2.0);
+
+        assert!(clear.light_intensity > rainy.light_intensity);
+        assert!((clear.light_intensity - 0.6).abs() < 1e-6);
+
+        // Windier in the afternoon
+        assert!(rainy.ray_proportion > clear.ray_proportion);
+        assert!(snowy.ray_proportion < clear.ray_proportion);
+
+        // Freezing is bluer than 18°C
+        assert!(snowy.current_color.z > clear.current_color.z);
+        assert!(snowy.current_color.x < clear.current_color.x);
+
+        // Units don't change the look
+        let converted = forecast.clone().in_units(Units::Imperial);
+        let imperial = WeatherLook::of(&converted.entries[1]);
+        assert!((imperial.ray_proportion - rainy.ray_proportion).abs() < 1e-5);
+        assert!((imperial.current_color - rainy.current_color).norm() < 1e-5);
+    }
+
+    #[test]
+    fn mapping_ranges() {
+        assert!((color(-20.0).z - 1.0).abs() < 1e-6);
+        assert!((color(40.0).x - 1.0).abs() < 1e-6);
+        assert!(color(15.0).y > 0.9);
+        assert_eq!(glow_amount(0.0), 8.0);
+        assert_eq!(ray_proportion(0.0), 2.0);
+        assert_eq!(ray_proportion(50.0), 10.0);
+        assert!((light_intensity(1.0, 100.0) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn current_entry() {
+        let forecast = forecast();
+        assert_eq!(entry_at(&forecast, 0).unwrap().time, 1540036800);
+        assert_eq!(entry_at(&forecast, 1540047600 + 60).unwrap().time, 1540047600);
+        assert_eq!(entry_at(&forecast, 2000000000).unwrap().time, 1540058400);
+    }
+}
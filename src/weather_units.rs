@@ -0,0 +1,202 @@
+use std::fmt;
+use std::str::FromStr;
+
+const MPH_PER_MPS: f64 = 3600.0 / 1609.344;
+
+/// The unit system the API answers in, sent as the `units` parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Units {
+    /// Kelvin and metres per second, what the API uses when not told otherwise.
+    Standard,
+    /// Celsius and metres per second.
+    Metric,
+    /// Fahrenheit and miles per hour.
+    Imperial,
+}
+
+impl Default for Units {
+    fn default() -> Self {
+        Units::Metric
+    }
+}
+
+impl Units {
+    pub fn to_api_param(self) -> &'static str {
+        match self {
+            Units::Standard => "standard",
+            Units::Metric => "metric",
+            Units::Imperial => "imperial",
+        }
+    }
+    /// Tags a temperature as read from an answer in these units.
+    pub fn temperature(self, value: f64) -> Temperature {
+        match self {
+            Units::Standard => Temperature::Kelvin(value),
+            Units::Metric => Temperature::Celsius(value),
+            Units::Imperial => Temperature::Fahrenheit(value),
+        }
+    }
+    /// Tags a speed as read from an answer in these units.
+    pub fn speed(self, value: f64) -> Speed {
+        match self {
+            Units::Standard | Units::Metric => Speed::MetresPerSecond(value),
+            Units::Imperial => Speed::MilesPerHour(value),
+        }
+    }
+}
+
+impl FromStr for Units {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_lowercase().as_str() {
+            "standard" | "kelvin" => Ok(Units::Standard),
+            "metric" | "celsius" => Ok(Units::Metric),
+            "imperial" | "fahrenheit" => Ok(Units::Imperial),
+            _ => Err(format!("unknown units \"{}\", try metric, imperial or standard", s)),
+        }
+    }
+}
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_api_param())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Temperature {
+    Kelvin(f64),
+    Celsius(f64),
+    Fahrenheit(f64),
+}
+
+impl Temperature {
+    /// The number, in whatever unit it is.
+    pub fn value(self) -> f64 {
+        match self {
+            Temperature::Kelvin(t) | Temperature::Celsius(t) | Temperature::Fahrenheit(t) => t,
+        }
+    }
+    pub fn kelvin(self) -> f64 {
+        match self {
+            Temperature::Kelvin(t) => t,
+            Temperature::Celsius(t) => t + 273.15,
+            Temperature::Fahrenheit(t) => (t - 32.0) / 1.8 + 273.15,
+        }
+    }
+    pub fn celsius(self) -> f64 {
+        match self {
+            Temperature::Celsius(t) => t,
+            other => other.kelvin() - 273.15,
+        }
+    }
+    pub fn fahrenheit(self) -> f64 {
+        match self {
+            Temperature::Fahrenheit(t) => t,
+            other => other.celsius() * 1.8 + 32.0,
+        }
+    }
+    pub fn in_units(self, units: Units) -> Temperature {
+        match units {
+            Units::Standard => Temperature::Kelvin(self.kelvin()),
+            Units::Metric => Temperature::Celsius(self.celsius()),
+            Units::Imperial => Temperature::Fahrenheit(self.fahrenheit()),
+        }
+    }
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = match *self {
+            Temperature::Kelvin(_) => "K",
+            Temperature::Celsius(_) => "°C",
+            Temperature::Fahrenheit(_) => "°F",
+        };
+        write!(f, "{:.1} {}", self.value(), unit)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Speed {
+    MetresPerSecond(f64),
+    MilesPerHour(f64),
+}
+
+impl Speed {
+    pub fn value(self) -> f64 {
+        match self {
+            Speed::MetresPerSecond(s) | Speed::MilesPerHour(s) => s,
+        }
+    }
+    pub fn metres_per_second(self) -> f64 {
+        match self {
+            Speed::MetresPerSecond(s) => s,
+            Speed::MilesPerHour(s) => s / MPH_PER_MPS,
+        }
+    }
+    pub fn miles_per_hour(self) -> f64 {
+        match self {
+            Speed::MetresPerSecond(s) => s * MPH_PER_MPS,
+            Speed::MilesPerHour(s) => s,
+        }
+    }
+    pub fn in_units(self, units: Units) -> Speed {
+        match units {
+            Units::Standard | Units::Metric => Speed::MetresPerSecond(self.metres_per_second()),
+            Units::Imperial => Speed::MilesPerHour(self.miles_per_hour()),
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Speed::MetresPerSecond(s) => write!(f, "{:.1} m/s", s),
+            Speed::MilesPerHour(s) => write!(f, "{:.1} mph", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use drafts::weather_units::{Speed, Temperature, Units};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn temperature_conversions() {
+        let boiling = [
+            Temperature::Kelvin(373.15),
+            Temperature::Celsius(100.0),
+            Temperature::Fahrenheit(212.0),
+        ];
+        for t in boiling.iter() {
+            assert!(close(t.kelvin(), 373.15), "{:?}", t);
+            assert!(close(t.celsius(), 100.0), "{:?}", t);
+            assert!(close(t.fahrenheit(), 212.0), "{:?}", t);
+        }
+        assert!(close(Temperature::Fahrenheit(-40.0).celsius(), -40.0));
+        let t = Temperature::Celsius(18.0).in_units(Units::Imperial);
+        assert!(close(t.value(), 64.4));
+        assert_eq!(t.to_string(), "64.4 °F");
+    }
+
+    #[test]
+    fn speed_conversions() {
+        assert!(close(Speed::MilesPerHour(60.0).metres_per_second(), 26.8224));
+        assert!(close(Speed::MetresPerSecond(26.8224).miles_per_hour(), 60.0));
+        assert_eq!(Units::Standard.speed(3.0), Speed::MetresPerSecond(3.0));
+        assert_eq!(Speed::MilesPerHour(10.0).in_units(Units::Imperial).to_string(), "10.0 mph");
+    }
+
+    #[test]
+    fn parse_units() {
+        assert_eq!("metric".parse(), Ok(Units::Metric));
+        assert_eq!("Imperial".parse(), Ok(Units::Imperial));
+        assert_eq!("standard".parse(), Ok(Units::Standard));
+        assert!("furlongs".parse::<Units>().is_err());
+        assert_eq!(Units::Imperial.to_string(), "imperial");
+    }
+}